@@ -2,36 +2,59 @@ import { createStarryNight, all } from '@wooorm/starry-night'
 import { toHtml } from 'hast-util-to-html'
 
 import { createInterface } from 'node:readline'
-import { stdin, stdout, exit } from 'node:process'
+import { stdin, stdout, exit, argv } from 'node:process'
+
+// Settings from mdnya, as JSON in the first argument
+const { classPrefix = 'pl-' } = JSON.parse(argv[2] ?? '{}')
+
+// Starry Night's classes all start with pl-
+const renameClasses = (node) => {
+    const classes = node.properties?.className
+    if (classPrefix !== 'pl-' && Array.isArray(classes)) {
+        node.properties.className = classes.map((c) => c.startsWith('pl-') ? classPrefix + c.slice(3) : c)
+    }
+    node.children?.forEach(renameClasses)
+    return node
+}
+
+// One JSON object per line in each direction.
+// Requests are {id, lang, code}, and each gets one response with the same id:
+// {id, html} or {id, error: {kind, message}}
 
 const rl = createInterface({
   input: stdin,
-  output: stdout,
   terminal: false
 });
 
-let lang = null;
-let code = '';
+const respond = (response) => stdout.write(JSON.stringify(response) + '\n')
 
 const starryNight = await createStarryNight(all)
-console.log('ready')
+respond({ ready: true })
 
 rl.on('line', (line) => {
-    if (line && line[0] != '\t') { // new lang
-        lang = line;
-    } else if (line[0] == "\t") { // add code
-        code += line.slice(1) + '\n';
-    } else if (lang && line === "") { // output code
-        const s = starryNight.flagToScope(lang)
-        if (!s) {
-            console.log(code)
-        } else {
-            console.log(toHtml(starryNight.highlight(code, s)))
-        }
-        console.log("\x04") // EOT
-        lang = null;
-        code = '';
-    } else  {
-        exit(0);
+    let request;
+    try {
+        request = JSON.parse(line);
+    } catch (e) {
+        respond({ id: null, error: { kind: 'bad-request', message: e.message } });
+        return;
+    }
+    const { id, lang, code } = request ?? {};
+    if (typeof id !== 'number' || typeof lang !== 'string' || typeof code !== 'string') {
+        respond({ id: id ?? null, error: { kind: 'bad-request', message: 'expected {id, lang, code}' } });
+        return;
+    }
+
+    const scope = starryNight.flagToScope(lang)
+    if (!scope) {
+        respond({ id, error: { kind: 'unknown-language', message: `unknown language: ${lang}` } });
+        return;
+    }
+    try {
+        respond({ id, html: toHtml(renameClasses(starryNight.highlight(code, scope))) });
+    } catch (e) {
+        respond({ id, error: { kind: 'highlight', message: String(e) } });
     }
 });
+
+rl.on('close', () => exit(0));
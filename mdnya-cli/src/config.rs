@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobMatcher};
+use mdnya::MdnyaOptions;
+use serde::Deserialize;
+
+pub const CONFIG_FILE_NAME: &str = "mdnya.toml";
+
+/// Options that change how markdown is rendered. Keys are the same as the command line flags.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RenderSettings {
+    pub close_all_tags: Option<bool>,
+    pub doc_tags: Option<Vec<String>>,
+    pub section_tags: Option<String>,
+    pub heading_level: Option<u8>,
+    pub no_ids: Option<bool>,
+    pub math: Option<bool>,
+}
+
+impl RenderSettings {
+    /// Use values from `fallback` where these settings are unset
+    pub fn or(self, fallback: RenderSettings) -> RenderSettings {
+        RenderSettings {
+            close_all_tags: self.close_all_tags.or(fallback.close_all_tags),
+            doc_tags: self.doc_tags.or(fallback.doc_tags),
+            section_tags: self.section_tags.or(fallback.section_tags),
+            heading_level: self.heading_level.or(fallback.heading_level),
+            no_ids: self.no_ids.or(fallback.no_ids),
+            math: self.math.or(fallback.math),
+        }
+    }
+
+    pub fn to_options(&self) -> MdnyaOptions {
+        MdnyaOptions::default()
+            .with_close_all_tags(self.close_all_tags.unwrap_or(false))
+            .with_wrap_sections(self.section_tags.clone())
+            .with_wrap_document(self.doc_tags.clone())
+            .with_heading_level(self.heading_level.unwrap_or(1))
+            .with_header_ids(!self.no_ids.unwrap_or(false))
+            .with_math(self.math.unwrap_or(false))
+    }
+}
+
+/// `meta = true` for metadata next to each input, or `meta = "dir"`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum MetaSetting {
+    Enabled(bool),
+    Path(PathBuf),
+}
+
+/// Settings for files matching a glob, relative to the config file
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Override {
+    pub glob: String,
+    #[serde(flatten)]
+    pub settings: RenderSettings,
+    // anything not used above, which is an error
+    #[serde(flatten)]
+    unknown: toml::Table,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub output: Option<PathBuf>,
+    pub meta: Option<MetaSetting>,
+    pub ext: Option<String>,
+    pub verbose: Option<bool>,
+    pub recursive: Option<bool>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub jobs: Option<usize>,
+    pub watch: Option<bool>,
+    #[serde(flatten)]
+    pub render: RenderSettings,
+    #[serde(rename = "override")]
+    pub overrides: Vec<Override>,
+    // anything not used above, which is an error.
+    // deny_unknown_fields doesn't work with flatten
+    #[serde(flatten)]
+    unknown: toml::Table,
+
+    /// Directory containing the config file
+    #[serde(skip)]
+    pub root: PathBuf,
+    #[serde(skip)]
+    matchers: Vec<GlobMatcher>,
+}
+
+impl Config {
+    /// Find the nearest config file in the input's directory or its parents
+    pub fn discover(input: &Path) -> Option<PathBuf> {
+        let input = input.canonicalize().ok()?;
+        let start = if input.is_dir() { input.as_path() } else { input.parent()? };
+        start.ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    pub fn load(path: &Path) -> crate::Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| format!("invalid config {}: {e}", path.display()))?;
+        let unknown = config.unknown.keys()
+            .chain(config.overrides.iter().flat_map(|o| o.unknown.keys()))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(format!("invalid config {}: unknown keys {}", path.display(), unknown.join(", ")).into());
+        }
+
+        config.root = path.canonicalize()?.parent().map(Path::to_path_buf).unwrap_or_default();
+        config.matchers = config.overrides.iter()
+            .map(|o| Glob::new(&o.glob).map(|glob| glob.compile_matcher()))
+            .collect::<Result<_, _>>()?;
+
+        // paths in the config file are relative to it
+        let root = &config.root;
+        config.output = config.output.map(|path| resolve(root, path));
+        config.meta = config.meta.map(|meta| match meta {
+            MetaSetting::Path(path) => MetaSetting::Path(resolve(root, path)),
+            enabled => enabled,
+        });
+
+        Ok(config)
+    }
+
+    /// Settings for one input file. Settings from the command line take priority,
+    /// then later matching overrides over earlier ones
+    pub fn settings_for(&self, input: &Path, cli: &RenderSettings) -> RenderSettings {
+        let input = input.canonicalize().unwrap_or_else(|_| input.to_path_buf());
+        let relative = input.strip_prefix(&self.root).unwrap_or(&input);
+
+        let config = self.overrides.iter().zip(&self.matchers)
+            .filter(|(_, matcher)| matcher.is_match(relative))
+            .fold(self.render.clone(), |settings, (o, _)| o.settings.clone().or(settings));
+        cli.clone().or(config)
+    }
+}
+
+fn resolve(root: &Path, path: PathBuf) -> PathBuf {
+    if path == Path::new("stdout") {
+        path
+    } else {
+        root.join(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a config file in a new temporary directory, with an `api` directory beside it
+    fn load(name: &str, text: &str) -> crate::Result<Config> {
+        let dir = std::env::temp_dir().join(format!("mdnya-config-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(dir.join("api"))?;
+        let path = dir.join(CONFIG_FILE_NAME);
+        std::fs::write(&path, text)?;
+        Config::load(&path)
+    }
+
+    #[test]
+    fn later_overrides_win() {
+        let config = load("overrides", r#"
+            heading-level = 1
+            math = true
+            [[override]]
+            glob = "api/*.md"
+            heading-level = 2
+            [[override]]
+            glob = "api/b.md"
+            heading-level = 3
+        "#).unwrap();
+        let cli = RenderSettings::default();
+        let a = config.settings_for(&config.root.join("api/a.md"), &cli);
+        let b = config.settings_for(&config.root.join("api/b.md"), &cli);
+        let c = config.settings_for(&config.root.join("c.md"), &cli);
+        assert_eq!((a.heading_level, b.heading_level, c.heading_level), (Some(2), Some(3), Some(1)));
+        assert_eq!(b.math, Some(true));
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let config = load("cli", r#"
+            section-tags = "section"
+            [[override]]
+            glob = "*.md"
+            heading-level = 2
+        "#).unwrap();
+        let cli = RenderSettings { heading_level: Some(4), .. Default::default() };
+        let settings = config.settings_for(&config.root.join("a.md"), &cli);
+        assert_eq!(settings.heading_level, Some(4));
+        assert_eq!(settings.section_tags.as_deref(), Some("section"));
+    }
+
+    #[test]
+    fn paths_relative_to_config() {
+        let config = load("paths", "output = \"out\"\nmeta = \"meta\"\n").unwrap();
+        assert_eq!(config.output, Some(config.root.join("out")));
+        assert!(matches!(config.meta, Some(MetaSetting::Path(path)) if path == config.root.join("meta")));
+        let config = load("stdout", "output = \"stdout\"\n").unwrap();
+        assert_eq!(config.output, Some(PathBuf::from("stdout")));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let error = load("unknown", "heading_lvl = 3\n").unwrap_err();
+        assert!(error.to_string().contains("heading_lvl"), "{error}");
+        let error = load("unknown-override", "[[override]]\nglob = \"*\"\nmaht = true\n").unwrap_err();
+        assert!(error.to_string().contains("maht"), "{error}");
+    }
+}
@@ -1,8 +1,12 @@
-use std::{path::PathBuf, io::Write};
+use std::{path::{Path, PathBuf}, io::Write, sync::Arc};
 
-use mdnya::MdnyaOptions;
+use mdnya::{Highlighter, MdnyaOptions};
 use clap::Parser as clapParser;
 
+mod config;
+
+use config::{Config, MetaSetting, RenderSettings};
+
 #[derive(clapParser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Options {
@@ -35,9 +39,9 @@ struct Options {
     #[clap(short, long)]
     verbose: bool,
 
-    /// Increase base heading level to this number
-    #[clap(short='l', long="heading-level", default_value="1")]
-    heading_level: u8,
+    /// Increase base heading level to this number (default: 1)
+    #[clap(short='l', long="heading-level")]
+    heading_level: Option<u8>,
 
     /// Change to this extension for default output. 
     #[clap(long="ext")]
@@ -46,153 +50,407 @@ struct Options {
     /// Don't add id attributes to headings
     #[clap(long="no-ids")]
     no_ids: bool,
+
+    /// Parse $inline$ and $$ block math, for rendering client-side
+    #[clap(long)]
+    math: bool,
+
+    /// Convert markdown files in subdirectories too, skipping files ignored by .gitignore.
+    /// Output and metadata directories mirror the input directory structure
+    #[clap(short, long)]
+    recursive: bool,
+
+    /// Only convert files in the input directory matching these globs. Comma separated
+    #[clap(long, value_delimiter = ',')]
+    include: Option<Vec<String>>,
+
+    /// Skip files in the input directory matching these globs. Comma separated
+    #[clap(long, value_delimiter = ',')]
+    exclude: Option<Vec<String>>,
+
+    /// Keep running, converting markdown files again when they change
+    #[clap(short, long)]
+    watch: bool,
+
+    /// Convert once and exit, even if the config file sets watch
+    #[clap(long="no-watch", conflicts_with = "watch")]
+    no_watch: bool,
+
+    /// Config file to use instead of the nearest mdnya.toml to the input
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Render this many files at once (default: 1). Output is the same as rendering one at a time
+    #[clap(short, long)]
+    jobs: Option<usize>,
+}
+
+impl Options {
+    // rendering settings which were passed on the command line
+    fn render_settings(&self) -> RenderSettings {
+        RenderSettings {
+            close_all_tags: self.close_all_tags.then_some(true),
+            doc_tags: self.document_tags.clone(),
+            section_tags: self.section_tags.clone(),
+            heading_level: self.heading_level,
+            no_ids: self.no_ids.then_some(true),
+            math: self.math.then_some(true),
+        }
+    }
 }
 
-fn convert_one(input: &PathBuf, output: &mut Box<dyn Write>, meta_output: Option<Box<dyn Write>>, options: &MdnyaOptions) -> mdnya::Result<()> {
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A rendered file, ready to be written
+struct Rendered {
+    html: Vec<u8>,
+    meta: mdnya::DocumentMetaData,
+}
+
+fn render_one(input: &PathBuf, options: MdnyaOptions) -> Result<Rendered> {
     let source_code = std::fs::read_to_string(input)?.replace('\r', "");
-    let meta = mdnya::render_markdown(source_code, output, options.clone())?;
-    if let Some(mut meta_output) = meta_output {
-        let json = serde_json::to_string_pretty(&meta)?;
+    // render fully before opening the output, so a failed render leaves the last output in place
+    let mut html = vec![];
+    let meta = mdnya::render_markdown(source_code, &mut html, options)?;
+    Ok(Rendered { html, meta })
+}
+
+fn write_one(input: &Path, destinations: &Destinations, rendered: Rendered) -> Result<()> {
+    let mut output = destinations.output_for(input)?;
+    output.write_all(&rendered.html)?;
+    // flushed here, since errors are lost when a BufWriter flushes on drop
+    output.flush()?;
+    if let Some(mut meta_output) = destinations.meta_for(input)? {
+        let json = serde_json::to_string_pretty(&rendered.meta)?;
         write!(meta_output, "{}", json)?;
+        meta_output.flush()?;
+    }
+    Ok(())
+}
+
+/// Where to write the HTML and metadata for each input file
+#[derive(Debug)]
+struct Destinations {
+    input_root: PathBuf,
+    output: Option<PathBuf>,
+    meta: Option<Option<PathBuf>>,
+    ext: String,
+}
+
+impl Destinations {
+    fn output_for(&self, input_file: &Path) -> Result<Box<dyn Write>> {
+        match &self.output {
+            Some(path) if path == Path::new("stdout") => Ok(Box::new(std::io::stdout())),
+            Some(path) if path.is_dir() => open_write(&mirrored_path(path, &self.input_root, input_file, &self.ext)),
+            Some(path) => open_write(path),
+            None => open_write(&input_file.with_extension(&self.ext)),
+        }
+    }
+
+    fn meta_for(&self, input_file: &Path) -> Result<Option<Box<dyn Write>>> {
+        match &self.meta {
+            Some(Some(path)) if path == Path::new("stdout") => Ok(Some(Box::new(std::io::stdout()))),
+            Some(Some(path)) if path.is_dir() => open_write(&mirrored_path(path, &self.input_root, input_file, "json")).map(Some),
+            Some(Some(path)) => open_write(path).map(Some),
+            Some(None) => open_write(&input_file.with_extension("json")).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// convert each file, logging any that fail. returns the number of failures.
+// files are rendered on `jobs` threads, but written and logged in order
+fn convert_files(
+    input_files: &[PathBuf],
+    destinations: &Destinations,
+    options_for: &(impl Fn(&PathBuf) -> MdnyaOptions + Sync),
+    jobs: usize,
+) -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let next_input = AtomicUsize::new(0);
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, input_files.len().max(1)) {
+            let tx = tx.clone();
+            let next_input = &next_input;
+            scope.spawn(move || loop {
+                let i = next_input.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = input_files.get(i) else { break };
+                let render_start = std::time::Instant::now();
+                let rendered = render_one(input, options_for(input));
+                if tx.send((i, rendered, render_start.elapsed())).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut failed = 0;
+        let mut pending = std::collections::BTreeMap::new();
+        let mut next_write = 0;
+        for (i, rendered, elapsed) in rx {
+            pending.insert(i, (rendered, elapsed));
+            while let Some((rendered, elapsed)) = pending.remove(&next_write) {
+                let input = &input_files[next_write];
+                next_write += 1;
+                match rendered.and_then(|rendered| write_one(input, destinations, rendered)) {
+                    Ok(()) => justlogfox::log_debug!("rendered {:?} in {:?}", input, elapsed),
+                    Err(e) => {
+                        justlogfox::log_error!("failed to convert {:?}: {}", input, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        failed
+    })
+}
+
+// convert markdown files again as they change, until interrupted
+fn watch(opts: &Options, destinations: &Destinations, options_for: &(impl Fn(&PathBuf) -> MdnyaOptions + Sync)) -> Result<()> {
+    use notify::{Watcher, RecursiveMode, EventKind};
+
+    let (watch_dir, mode) =
+        if opts.input_file.is_dir() {
+            let mode = if opts.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+            (opts.input_file.clone(), mode)
+        } else if destinations.input_root.as_os_str().is_empty() {
+            (PathBuf::from("."), RecursiveMode::NonRecursive)
+        } else {
+            // editors may replace the file when saving, so watch its directory instead
+            (destinations.input_root.clone(), RecursiveMode::NonRecursive)
+        };
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&watch_dir, mode)?;
+    justlogfox::log_info!("watching {:?} for changes", watch_dir);
+
+    while let Ok(first) = rx.recv() {
+        // one save can be several events, so wait for them to settle
+        let mut events = vec![first];
+        while let Ok(event) = rx.recv_timeout(std::time::Duration::from_millis(100)) {
+            events.push(event);
+        }
+
+        let mut changed = std::collections::BTreeSet::new();
+        for event in events {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) =>
+                    changed.extend(event.paths.iter().filter_map(|path| path.canonicalize().ok())),
+                Ok(_) => (),
+                Err(e) => justlogfox::log_error!("watch error: {}", e),
+            }
+        }
+
+        // only the files a full run would convert
+        let candidates =
+            if opts.input_file.is_dir() {
+                match find_markdown_files(
+                    &opts.input_file,
+                    opts.recursive,
+                    opts.include.as_deref().unwrap_or_default(),
+                    opts.exclude.as_deref().unwrap_or_default())
+                {
+                    Ok(files) => files,
+                    Err(e) => {
+                        justlogfox::log_error!("failed to list markdown files: {}", e);
+                        continue;
+                    }
+                }
+            } else {
+                vec![opts.input_file.clone()]
+            };
+        let to_convert = candidates.into_iter()
+            .filter(|file| file.canonicalize().map(|path| changed.contains(&path)).unwrap_or(false))
+            .collect::<Vec<_>>();
+
+        if to_convert.is_empty() {
+            continue;
+        }
+
+        let failed = convert_files(&to_convert, destinations, options_for, opts.jobs.unwrap_or(1));
+        justlogfox::log_info!("converted {} of {} changed files", (to_convert.len() - failed), (to_convert.len()));
     }
+
     Ok(())
 }
 
-fn open_write(path: &PathBuf) -> Box<dyn Write> {
-    Box::new(std::io::BufWriter::new(std::fs::File::create(path).unwrap())) as Box<dyn Write>
+type SharedHighlighter = Arc<dyn Highlighter + Send + Sync>;
+
+// one highlighter for all files, so node is only started once
+#[cfg(feature = "syntect")]
+fn new_highlighter() -> Option<SharedHighlighter> {
+    Some(Arc::new(mdnya::SyntectHighlighter::new()))
+}
+
+#[cfg(all(feature = "starry-night", not(feature = "syntect")))]
+fn new_highlighter() -> Option<SharedHighlighter> {
+    let mut highlighter = mdnya::CachedHighlighter::new(
+        mdnya::StarryHighlighter::new(mdnya::StarryOptions::default()));
+    if let Some(dir) = mdnya::default_cache_dir() {
+        highlighter = highlighter.with_disk_cache(dir);
+    }
+    Some(Arc::new(highlighter))
+}
+
+#[cfg(not(any(feature = "starry-night", feature = "syntect")))]
+fn new_highlighter() -> Option<SharedHighlighter> {
+    None
+}
+
+fn open_write(path: &PathBuf) -> Result<Box<dyn Write>> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("could not create directory {:?}: {}", parent, e))?;
+    }
+    let file = std::fs::File::create(path)
+        .map_err(|e| format!("could not create {:?}: {}", path, e))?;
+    Ok(Box::new(std::io::BufWriter::new(file)))
+}
+
+fn find_markdown_files(dir: &Path, recursive: bool, include: &[String], exclude: &[String]) -> Result<Vec<PathBuf>> {
+    let mut overrides = ignore::overrides::OverrideBuilder::new(dir);
+    for glob in include {
+        overrides.add(glob)?;
+    }
+    for glob in exclude {
+        overrides.add(&format!("!{glob}"))?;
+    }
+
+    let mut files = ignore::WalkBuilder::new(dir)
+        .max_depth(if recursive { None } else { Some(1) })
+        .standard_filters(recursive)
+        .require_git(false)
+        .overrides(overrides.build()?)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .filter(|entry| entry.path().extension() == Some("md".as_ref()))
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+// the path in an output directory for an input file, keeping its place relative to the input root
+fn mirrored_path(out_dir: &Path, input_root: &Path, input_file: &Path, ext: &str) -> PathBuf {
+    let relative = input_file.strip_prefix(input_root)
+        .unwrap_or_else(|_| input_file.file_name().unwrap().as_ref());
+    let mut out_path = out_dir.join(relative);
+    out_path.set_extension(ext);
+    out_path
 }
 
-fn main() -> mdnya::Result<()> {
+fn main() -> Result<()> {
 
-    let opts = Options::parse();
+    let mut opts = Options::parse();
+
+    let config_path = opts.config.clone().or_else(|| Config::discover(&opts.input_file));
+    let config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // command line flags take precedence over the config file
+    opts.verbose |= config.verbose.unwrap_or(false);
+    opts.output_file = opts.output_file.or(config.output.clone());
+    opts.output_ext = opts.output_ext.or(config.ext.clone());
+    opts.recursive |= config.recursive.unwrap_or(false);
+    opts.include = opts.include.or(config.include.clone());
+    opts.exclude = opts.exclude.or(config.exclude.clone());
+    opts.jobs = opts.jobs.or(config.jobs);
+    opts.watch |= !opts.no_watch && config.watch.unwrap_or(false);
+    if opts.metadata_file.is_none() {
+        opts.metadata_file = match &config.meta {
+            Some(MetaSetting::Enabled(true)) => Some(None),
+            Some(MetaSetting::Path(path)) => Some(Some(path.clone())),
+            Some(MetaSetting::Enabled(false)) | None => None,
+        };
+    }
+    let cli_settings = opts.render_settings();
 
     if opts.verbose {
         justlogfox::set_log_level(justlogfox::LogLevel::Debug);
+    } else if opts.watch {
+        // to show when files are converted
+        justlogfox::set_log_level(justlogfox::LogLevel::Info);
     } else {
         justlogfox::set_log_level(justlogfox::LogLevel::Warn);
     }
 
     justlogfox::set_crate_color!(justlogfox::CssColors::Pink);
 
+    justlogfox::log_debug!("config file: {:?}", config_path);
     justlogfox::log_trace!("Close all tags: {}", (opts.close_all_tags));
 
-    let input_files =
+    let (input_root, input_files) =
         if opts.input_file.is_dir() {
             justlogfox::log_trace!("input is directory {:?}", opts.input_file);
-            let files = std::fs::read_dir(&opts.input_file)?
-                .filter_map(|entry| entry.ok())
-                .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
-                .filter(|entry| entry.path().extension() == Some("md".as_ref()))
-                .map(|entry| entry.path())
-                .collect::<Vec<_>>();
+            let files = find_markdown_files(
+                &opts.input_file,
+                opts.recursive,
+                opts.include.as_deref().unwrap_or_default(),
+                opts.exclude.as_deref().unwrap_or_default())?;
             if files.is_empty() {
                 justlogfox::log_warn!("No markdown files found in {:?}", opts.input_file);
             }
-            files
+            (opts.input_file.clone(), files)
         } else {
             justlogfox::log_trace!("input is file {:?}", opts.input_file);
-            vec![opts.input_file.clone()]
+            let root = opts.input_file.parent().map(Path::to_path_buf).unwrap_or_default();
+            (root, vec![opts.input_file.clone()])
         };
 
-    let ext = opts.output_ext.unwrap_or("html".to_owned());
+    let destinations = Destinations {
+        input_root,
+        output: opts.output_file.clone(),
+        meta: opts.metadata_file.clone(),
+        ext: opts.output_ext.clone().unwrap_or("html".to_owned()),
+    };
+    justlogfox::log_trace!("destinations: {:?}", destinations);
 
-    let outputs =
-        match &opts.output_file {
-            Some(path) if path == &PathBuf::from("stdout") => {
-                justlogfox::log_trace!("output to stdout");
-                vec![()].repeat(input_files.len())
-                .iter().map(
-                    |_| Box::new(std::io::stdout()) as Box<dyn Write>)
-                .collect()
-            }
-            Some(path) if path.is_dir() => {
-                std::fs::create_dir_all(path).unwrap();
-                justlogfox::log_trace!("output to directory {:?}", path);
-                input_files.iter().map(|input_file| {
-                    let out_filename = input_file.file_name().unwrap();
-                    let mut out_path = path.join(out_filename);
-                    out_path.set_extension(&ext);
-                    open_write(&out_path)
-                }).collect()
-            }
-            Some(path) if input_files.len() == 1 => {
-                justlogfox::log_trace!("output to one file {:?}", path);
-                vec![open_write(path)]
-            }
-            Some(path) => {
+    if input_files.len() > 1 {
+        if let Some(path) = &destinations.output {
+            if path != Path::new("stdout") && !path.is_dir() {
                 justlogfox::log_error!("multiple input files, but output is not a directory: {:?}", path);
                 println!("Try specifying a directory instead, or omit --output");
                 std::process::exit(1)
             }
-            None => {
-                justlogfox::log_trace!("output to renamed  in .");
-                input_files.iter().map(|input_file| {
-                    let out_filename = input_file.file_name().unwrap();
-                    let mut out_path = input_file.parent().unwrap().join(out_filename);
-                    out_path.set_extension(&ext);
-                    open_write(&out_path)
-                }).collect()
-            }
-        };
-
-    let meta_outputs =
-        match &opts.metadata_file {
-            Some(Some(path)) if path == &PathBuf::from("stdout") => {
-                justlogfox::log_trace!("metadata to stdout");
-                vec![()].repeat(input_files.len())
-                .iter().map(
-                    |_| Some(Box::new(std::io::stdout()) as Box<dyn Write>))
-                .collect()
-            }
-            Some(Some(path)) if path.is_dir() => {
-                std::fs::create_dir_all(path).unwrap();
-                justlogfox::log_trace!("metadata to directory {:?}", path);
-                input_files.iter().map(|input_file| {
-                    let out_filename = input_file.file_name().unwrap();
-                    let mut out_path = path.join(out_filename);
-                    out_path.set_extension("json");
-                    Some(open_write(&out_path))
-                }).collect()
-            }
-            Some(Some(path)) if input_files.len() == 1 => {
-                justlogfox::log_trace!("metadata to one file {:?}", path);
-                vec![Some(open_write(path))]
-            }
-            Some(Some(path)) => {
+        }
+        if let Some(Some(path)) = &destinations.meta {
+            if path != Path::new("stdout") && !path.is_dir() {
                 justlogfox::log_error!("multiple input files, but meta is not a directory: {:?}", path);
                 println!("Try specifying a directory instead, or omit the argument to --meta");
                 std::process::exit(1)
             }
-            Some(None) => {
-                justlogfox::log_trace!("metadata to .");
-                input_files.iter().map(|input_file| {
-                    let out_filename = input_file.file_name().unwrap();
-                    let mut out_path = input_file.parent().unwrap().join(out_filename);
-                    out_path.set_extension("json");
-                    Some(open_write(&out_path))
-                }).collect()
-            }
-            None => {
-                justlogfox::log_trace!("no metadata output");
-                input_files.iter().map(|_| None).collect()
-            }
-        };
+        }
+    }
     
     let load_start = std::time::Instant::now();
 
-    let options = 
-        MdnyaOptions::new(opts.close_all_tags, opts.section_tags, opts.document_tags, opts.heading_level, !opts.no_ids)
-        .with_starry_night();
+    let highlighter = new_highlighter();
+    let options_for = |input: &PathBuf| -> MdnyaOptions {
+        let options = config.settings_for(input, &cli_settings).to_options();
+        match &highlighter {
+            Some(highlighter) => options.with_highlighter(highlighter.clone()),
+            None => options,
+        }
+    };
 
     justlogfox::log_debug!("setup took {:?}", (load_start.elapsed()));
 
-    for ((input, mut output), meta) in input_files.iter().zip(outputs).zip(meta_outputs) {
-        justlogfox::log_debug!("rendering {:?}", input);
-        let render_start = std::time::Instant::now();
+    let failed = convert_files(&input_files, &destinations, &options_for, opts.jobs.unwrap_or(1));
 
-        convert_one(input, &mut output, meta, &options)?;
+    if opts.watch {
+        return watch(&opts, &destinations, &options_for);
+    }
 
-        justlogfox::log_debug!("mdnya render() took {:?}", (render_start.elapsed()));
+    if failed > 0 {
+        justlogfox::log_error!("{} of {} files failed to convert", failed, (input_files.len()));
+        std::process::exit(1);
     }
 
     Ok(())
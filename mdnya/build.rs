@@ -7,6 +7,10 @@ pub const NPM: &str = "npm";
 fn main() {
     // only run if index.js changed:
     println!("cargo:rerun-if-changed=../index.js");
+    // starry night is the only part that needs nodejs
+    if std::env::var_os("CARGO_FEATURE_STARRY_NIGHT").is_none() {
+        return;
+    }
     let webpack = std::process::Command::new(NPM)
         .arg("run")
         .arg("build")
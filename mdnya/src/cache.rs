@@ -0,0 +1,181 @@
+use std::{
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    io::Result,
+    path::{Path, PathBuf},
+    sync::{Mutex, atomic::{AtomicUsize, Ordering}},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::starry::Highlighter;
+
+/// A cached code block, stored as json on disk.
+/// The language, code and meta are kept to check for hash collisions.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    lang: String,
+    code: String,
+    meta: Option<String>,
+    html: String,
+}
+
+/// A code block's language, code and meta
+type Key = (String, String, Option<String>);
+
+// written when a cache folder is opened, so folders in use are never removed
+const LAST_USED: &str = "last-used";
+// how long before unused caches are removed, from older versions of mdnya or from other settings
+const OLD_VERSION_UNUSED: Duration = Duration::from_secs(60 * 60);
+const OTHER_SETTINGS_UNUSED: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// `.mdnya/cache` in the local data directory, e.g. `~/.local/share` on Linux
+pub fn default_cache_dir() -> Option<PathBuf> {
+    Some(dirs::data_local_dir()?.join(".mdnya").join("cache"))
+}
+
+/// Remembers what another highlighter made of each code block, in memory and optionally on disk.
+/// Only highlighted output is kept, since escaped code may be a fallback after node crashed.
+pub struct CachedHighlighter<H> {
+    inner: H,
+    memory: Mutex<HashMap<Key, String>>,
+    // a directory only for this version of mdnya and settings of the highlighter
+    disk: Option<PathBuf>,
+}
+
+impl<H: Highlighter> CachedHighlighter<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            memory: Mutex::new(HashMap::new()),
+            disk: None,
+        }
+    }
+
+    /// Also keep output in `dir`, across runs.
+    /// Caches there which haven't been used in a while, by older versions or other settings, are removed
+    pub fn with_disk_cache(self, dir: PathBuf) -> Self {
+        let settings = format!("{:016x}", self.settings());
+        let disk = dir.join(env!("CARGO_PKG_VERSION")).join(&settings);
+        let opened = std::fs::create_dir_all(&disk)
+            .and_then(|_| std::fs::write(disk.join(LAST_USED), ""));
+        if let Err(e) = opened {
+            justlogfox::log_warn!("not caching highlighted code in {:?}: {}", disk, e);
+            return self;
+        }
+        remove_unused(&dir, &disk);
+        Self { disk: Some(disk), .. self }
+    }
+
+    // changes with the highlighter and its settings, its bundled js, and its grammars
+    fn settings(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        std::any::type_name::<H>().hash(&mut hasher);
+        self.inner.fingerprint().hash(&mut hasher);
+        #[cfg(feature = "starry-night")]
+        crate::starry::INDEXJS_SRC.hash(&mut hasher);
+        #[cfg(feature = "tree-sitter")]
+        crate::grammars::fingerprint(&mut hasher);
+        hasher.finish()
+    }
+
+    fn path_for(&self, lang: &str, code: &str, meta: Option<&str>) -> Option<PathBuf> {
+        // DefaultHasher may change between rust versions, which only costs a miss
+        let mut hasher = DefaultHasher::new();
+        (lang, code, meta).hash(&mut hasher);
+        Some(self.disk.as_ref()?.join(format!("{:016x}.json", hasher.finish())))
+    }
+
+    fn read_disk(&self, lang: &str, code: &str, meta: Option<&str>) -> Option<String> {
+        let text = std::fs::read_to_string(self.path_for(lang, code, meta)?).ok()?;
+        let entry: Entry = serde_json::from_str(&text).ok()?;
+        (entry.lang == lang && entry.code == code && entry.meta.as_deref() == meta).then_some(entry.html)
+    }
+
+    fn write_disk(&self, lang: &str, code: &str, meta: Option<&str>, html: &str) {
+        static TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+        let Some(path) = self.path_for(lang, code, meta) else { return };
+        let entry = Entry {
+            lang: lang.to_owned(),
+            code: code.to_owned(),
+            meta: meta.map(str::to_owned),
+            html: html.to_owned(),
+        };
+        // written whole then renamed, so other threads and processes never read part of it
+        let temp = path.with_extension(format!("{}-{}.tmp",
+            std::process::id(), TEMP_ID.fetch_add(1, Ordering::Relaxed)));
+        let result = serde_json::to_string(&entry).map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&temp, json))
+            .and_then(|_| std::fs::rename(&temp, &path));
+        if let Err(e) = result {
+            justlogfox::log_debug!("failed to cache highlighted code in {:?}: {}", path, e);
+            let _ = std::fs::remove_file(&temp);
+        }
+    }
+}
+
+// remove caches in `dir` other than `current`, if they haven't been opened recently.
+// only folders mdnya made are removed: a settings folder with a marker, in a version folder
+fn remove_unused(dir: &Path, current: &Path) {
+    let now = SystemTime::now();
+    for version in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let name = version.file_name();
+        if !name.to_str().is_some_and(is_version) || !version.path().is_dir() {
+            continue;
+        }
+        let is_current = name == env!("CARGO_PKG_VERSION");
+        let max_unused = if is_current { OTHER_SETTINGS_UNUSED } else { OLD_VERSION_UNUSED };
+        for settings in std::fs::read_dir(version.path()).into_iter().flatten().flatten() {
+            let path = settings.path();
+            let marker = path.join(LAST_USED);
+            if path == current || !path.is_dir() || !marker.is_file() {
+                continue;
+            }
+            // opened, or written to, most recently
+            let last_used = [marker, path.clone()].iter()
+                .filter_map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+                .max();
+            let unused = last_used.and_then(|time| now.duration_since(time).ok());
+            if unused.is_some_and(|unused| unused > max_unused) {
+                justlogfox::log_debug!("removing unused highlight cache {:?}", path);
+                let _ = std::fs::remove_dir_all(&path);
+            }
+        }
+        if !is_current {
+            // only succeeds once it is empty
+            let _ = std::fs::remove_dir(version.path());
+        }
+    }
+}
+
+// like `0.1.0` or `1.2.3-beta.1`
+fn is_version(name: &str) -> bool {
+    let core = name.split(['-', '+']).next().unwrap_or_default();
+    let parts = core.split('.').collect::<Vec<_>>();
+    parts.len() == 3 && parts.iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+impl<H: Highlighter> Highlighter for CachedHighlighter<H> {
+    fn highlight(&self, lang: &str, code: &str, meta: Option<&str>) -> Result<String> {
+        let key = (lang.to_owned(), code.to_owned(), meta.map(str::to_owned));
+        if let Some(html) = self.memory.lock().unwrap().get(&key) {
+            return Ok(html.clone());
+        }
+        if let Some(html) = self.read_disk(lang, code, meta) {
+            self.memory.lock().unwrap().insert(key, html.clone());
+            return Ok(html);
+        }
+
+        let html = self.inner.highlight(lang, code, meta)?;
+        if html != html_escape::encode_text(code) {
+            self.write_disk(lang, code, meta, &html);
+            self.memory.lock().unwrap().insert(key, html.clone());
+        }
+        Ok(html)
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.inner.fingerprint()
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The frontmatter is not a valid YAML or TOML map.
+    /// Line and column are 1-based, relative to the whole markdown document.
+    Frontmatter { message: String, line: usize, column: usize },
+    /// The markdown could not be parsed
+    Markdown(String),
+    /// The markdown parsed to a tree that mdnya doesn't know how to render
+    UnexpectedNode(String),
+    /// The highlighter failed on a code block
+    Highlight { lang: String, source: std::io::Error },
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Frontmatter { message, line, column } =>
+                write!(f, "invalid frontmatter at line {line}, column {column}: {message}"),
+            Error::Markdown(message) => write!(f, "failed to parse markdown: {message}"),
+            Error::UnexpectedNode(message) => write!(f, "unexpected markdown structure: {message}"),
+            Error::Highlight { lang, source } => write!(f, "failed to highlight {lang} code: {source}"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Highlight { source, .. } => Some(source),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,179 @@
+/// Settings from the meta of a code fence, the part of the info string after the language.
+/// For example: ```` ```rust {3,5-7} showLineNumbers startLine=10 title="src/main.rs" data-copy ````
+#[derive(Debug, Default)]
+pub struct FenceMeta {
+    /// Lines to highlight, as inclusive ranges of line numbers
+    pub highlight: Vec<(usize, usize)>,
+    /// Number of the first line, if the lines are numbered
+    pub start_line: Option<usize>,
+    /// Caption for the block, from `title` or `filename`
+    pub title: Option<String>,
+    /// `data-*` attributes for the `<pre>` element
+    pub data: Vec<(String, Option<String>)>,
+    /// Name shared by adjacent code blocks shown as tabs
+    pub group: Option<String>,
+}
+
+impl FenceMeta {
+    pub fn parse(meta: &str) -> Self {
+        let mut fence = FenceMeta::default();
+        for token in tokens(meta) {
+            if let Some(ranges) = token.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+                fence.highlight.extend(ranges.split(',').filter_map(parse_range));
+            } else if token == "showLineNumbers" {
+                fence.start_line.get_or_insert(1);
+            } else {
+                let (key, value) = match token.split_once('=') {
+                    Some((key, value)) => (key, Some(unquote(value))),
+                    None => (token, None),
+                };
+                match (key, value) {
+                    ("startLine", Some(value)) => match value.parse() {
+                        Ok(start) => fence.start_line = Some(start),
+                        Err(_) => justlogfox::log_warn!("startLine is not a number: {}", value),
+                    },
+                    ("title" | "filename", Some(value)) => fence.title = Some(value.to_owned()),
+                    ("group", Some(value)) => fence.group = Some(value.to_owned()),
+                    _ if key.starts_with("data-") => fence.data.push((key.to_owned(), value.map(str::to_owned))),
+                    _ => justlogfox::log_debug!("ignoring code fence meta: {}", token),
+                }
+            }
+        }
+        fence
+    }
+
+    /// Whether a line is selected, by its number
+    pub fn is_highlighted(&self, line: usize) -> bool {
+        self.highlight.iter().any(|(first, last)| (*first..=*last).contains(&line))
+    }
+}
+
+// `5` or `5-7`
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let range = range.trim();
+    let parsed = match range.split_once('-') {
+        Some((first, last)) => first.trim().parse().ok().zip(last.trim().parse().ok()),
+        None => range.parse().ok().map(|line| (line, line)),
+    };
+    if parsed.is_none() && !range.is_empty() {
+        justlogfox::log_warn!("invalid line range in code fence: {}", range);
+    }
+    parsed
+}
+
+/// Split meta on whitespace, except inside quotes or braces
+pub fn tokens(meta: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut quote = None;
+    let mut depth = 0;
+    for (i, c) in meta.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => depth -= 1,
+            (None, c) if c.is_whitespace() && depth <= 0 => {
+                if let Some(s) = start.take() {
+                    tokens.push(&meta[s..i]);
+                }
+                continue;
+            }
+            _ => (),
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(s) = start {
+        tokens.push(&meta[s..]);
+    }
+    tokens
+}
+
+/// One `key=value` of a code fence, without parsing the rest
+pub fn value<'m>(meta: &'m str, key: &str) -> Option<&'m str> {
+    tokens(meta).into_iter().find_map(|token| token.strip_prefix(key)?.strip_prefix('=')).map(unquote)
+}
+
+/// Remove one pair of matching quotes, if there are any
+pub fn unquote(value: &str) -> &str {
+    for q in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(q).and_then(|v| v.strip_suffix(q)) {
+            return inner;
+        }
+    }
+    value
+}
+
+/// The marker at the start of each line of a diff: `+`, `-`, a space for context, or nothing
+pub fn diff_marks(code: &str) -> Vec<&'static str> {
+    code.lines().map(|line| {
+        match line.chars().next() {
+            Some('+') => "+",
+            Some('-') => "-",
+            Some(' ') => " ",
+            _ => "",
+        }
+    }).collect()
+}
+
+/// Split highlighted HTML into lines which each have balanced tags.
+/// Elements spanning several lines are closed at the end of each line, and opened again on the next.
+/// A trailing line with only tags, left by a newline at the end of an element, is dropped.
+/// Empty HTML has no lines.
+pub fn balanced_lines(html: &str) -> Vec<String> {
+    if html.is_empty() {
+        return vec![];
+    }
+    let mut lines = vec![];
+    // the start tags of elements open at this point
+    let mut open: Vec<&str> = vec![];
+    let mut line = String::new();
+    let mut rest = html;
+
+    while let Some(i) = rest.find(['<', '\n']) {
+        line.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with('\n') {
+            for tag in open.iter().rev() {
+                line.push_str(&format!("</{}>", tag_name(tag)));
+            }
+            lines.push(std::mem::take(&mut line));
+            line.extend(open.iter().copied());
+            rest = &rest[1..];
+            continue;
+        }
+        let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+        let tag = &rest[..end];
+        if tag.starts_with("</") {
+            open.pop();
+        } else if !tag.ends_with("/>") {
+            open.push(tag);
+        }
+        line.push_str(tag);
+        rest = &rest[end..];
+    }
+    line.push_str(rest);
+    if lines.is_empty() || !open.is_empty() || line.contains(|c| c != '<') && !only_tags(&line) {
+        lines.push(line);
+    }
+    lines
+}
+
+fn only_tags(line: &str) -> bool {
+    let mut in_tag = false;
+    line.chars().all(|c| {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ => return in_tag,
+        }
+        true
+    })
+}
+
+fn tag_name(tag: &str) -> &str {
+    let name = tag.trim_start_matches('<');
+    let end = name.find(|c: char| c.is_whitespace() || c == '>' || c == '/').unwrap_or(name.len());
+    &name[..end]
+}
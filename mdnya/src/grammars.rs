@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    io::{Error, Result},
+    sync::OnceLock,
+};
+
+use lazy_static::lazy_static;
+use tree_sitter::Language;
+use tree_sitter_highlight::{HighlightConfiguration, HtmlRenderer};
+
+/// A tree-sitter grammar and its queries, enabled with a `grammar-*` cargo feature
+pub struct Grammar {
+    /// Name used to refer to this grammar, including from other grammars' injection queries
+    pub name: &'static str,
+    /// Other names for the language in code blocks
+    pub aliases: &'static [&'static str],
+    pub language: fn() -> Language,
+    pub highlights: &'static str,
+    pub injections: &'static str,
+    pub locals: &'static str,
+}
+
+pub const GRAMMARS: &[Grammar] = &[
+    #[cfg(feature = "grammar-csharp")]
+    Grammar {
+        name: "csharp",
+        aliases: &["c#", "cs"],
+        language: tree_sitter_c_sharp::language,
+        highlights: tree_sitter_c_sharp::HIGHLIGHT_QUERY,
+        injections: "",
+        locals: "",
+    },
+    #[cfg(feature = "grammar-rust")]
+    Grammar {
+        name: "rust",
+        aliases: &["rs"],
+        language: tree_sitter_rust::language,
+        highlights: tree_sitter_rust::HIGHLIGHT_QUERY,
+        injections: tree_sitter_rust::INJECTIONS_QUERY,
+        locals: "",
+    },
+    #[cfg(feature = "grammar-python")]
+    Grammar {
+        name: "python",
+        aliases: &["py", "python3"],
+        language: tree_sitter_python::language,
+        highlights: tree_sitter_python::HIGHLIGHT_QUERY,
+        injections: "",
+        locals: "",
+    },
+    #[cfg(feature = "grammar-json")]
+    Grammar {
+        name: "json",
+        aliases: &[],
+        language: tree_sitter_json::language,
+        highlights: tree_sitter_json::HIGHLIGHT_QUERY,
+        injections: "",
+        locals: "",
+    },
+    #[cfg(feature = "grammar-bash")]
+    Grammar {
+        name: "bash",
+        aliases: &["sh", "shell", "zsh"],
+        language: tree_sitter_bash::language,
+        // the crate doesn't export its queries
+        highlights: include_str!("../queries/bash/highlights.scm"),
+        injections: "",
+        locals: "",
+    },
+    #[cfg(feature = "grammar-html")]
+    Grammar {
+        name: "html",
+        aliases: &["htm"],
+        language: tree_sitter_html::language,
+        highlights: tree_sitter_html::HIGHLIGHT_QUERY,
+        injections: tree_sitter_html::INJECTION_QUERY,
+        locals: "",
+    },
+    #[cfg(feature = "grammar-javascript")]
+    Grammar {
+        name: "javascript",
+        aliases: &["js", "mjs", "cjs"],
+        language: tree_sitter_javascript::language,
+        highlights: tree_sitter_javascript::HIGHLIGHT_QUERY,
+        injections: tree_sitter_javascript::INJECTION_QUERY,
+        locals: tree_sitter_javascript::LOCALS_QUERY,
+    },
+    #[cfg(feature = "grammar-toml")]
+    Grammar {
+        name: "toml",
+        aliases: &[],
+        language: tree_sitter_toml::language,
+        highlights: tree_sitter_toml::HIGHLIGHT_QUERY,
+        injections: "",
+        locals: "",
+    },
+];
+
+const HL_NAMES: &[&str] = &[
+    "attribute",
+    "constant",
+    "function.builtin",
+    "function",
+    "keyword",
+    "operator",
+    "property",
+    "punctuation",
+    "punctuation.bracket",
+    "punctuation.delimiter",
+    "string",
+    "string.special",
+    "tag",
+    "type",
+    "type.builtin",
+    "variable",
+    "variable.builtin",
+    "variable.parameter",
+    "number",
+    "comment",
+];
+
+const HL_CLASSES: &[&str] = &[
+    "attribute",
+    "constant",
+    "function-builtin",
+    "function",
+    "keyword",
+    "operator",
+    "property",
+    "punctuation",
+    "punctuation-bracket",
+    "punctuation-delimiter",
+    "string",
+    "string-special",
+    "tag",
+    "type",
+    "type-builtin",
+    "variable",
+    "variable-builtin",
+    "variable-parameter",
+    "number",
+    "comment",
+];
+
+lazy_static! {
+    static ref HL_ATTRS: Vec<String> = HL_CLASSES.iter()
+        .map(|class| format!("class=\"{class}\""))
+        .collect();
+
+    // configurations are built the first time each language is highlighted
+    static ref CONFIGS: HashMap<&'static str, OnceLock<Option<HighlightConfiguration>>> = GRAMMARS.iter()
+        .map(|grammar| (grammar.name, OnceLock::new()))
+        .collect();
+}
+
+/// Find the grammar for a code block language, by name or alias
+pub fn find_grammar(lang: &str) -> Option<&'static Grammar> {
+    GRAMMARS.iter().find(|grammar| {
+        grammar.name.eq_ignore_ascii_case(lang)
+        || grammar.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(lang))
+    })
+}
+
+fn build_config(grammar: &Grammar) -> Option<HighlightConfiguration> {
+    let config = HighlightConfiguration::new(
+        (grammar.language)(), grammar.highlights, grammar.injections, grammar.locals);
+    match config {
+        Ok(mut config) => {
+            config.configure(HL_NAMES);
+            Some(config)
+        }
+        Err(e) => {
+            justlogfox::log_error!("invalid queries for {} grammar: {:?}", (grammar.name), e);
+            None
+        }
+    }
+}
+
+/// The cached highlight configuration for a language, if there is a grammar for it
+pub fn config_for(lang: &str) -> Option<&'static HighlightConfiguration> {
+    let grammar = find_grammar(lang)?;
+    CONFIGS[grammar.name].get_or_init(|| build_config(grammar)).as_ref()
+}
+
+/// Changes when the enabled grammars or their queries do, for invalidating cached output
+pub fn fingerprint(hasher: &mut impl Hasher) {
+    for grammar in GRAMMARS {
+        let language = (grammar.language)();
+        (grammar.name, grammar.highlights, grammar.injections, grammar.locals).hash(hasher);
+        (language.version(), language.node_kind_count()).hash(hasher);
+    }
+}
+
+/// Highlight code with tree-sitter, or None if there is no grammar for the language
+pub fn highlight(lang: &str, code: &str) -> Option<Result<String>> {
+    let config = config_for(lang)?;
+    justlogfox::log_trace!("highlight {} with tree-sitter", lang);
+    Some(render(config, code))
+}
+
+fn render(config: &HighlightConfiguration, code: &str) -> Result<String> {
+    let mut hl = tree_sitter_highlight::Highlighter::new();
+    // languages embedded in the code, like scripts in html, are found by name or alias.
+    // passing config_for directly would require the code to be 'static
+    #[allow(clippy::redundant_closure)]
+    let events = hl.highlight(config, code.as_bytes(), None, |name| config_for(name))
+        .map_err(|e| Error::other(format!("tree-sitter: {e}")))?;
+    let mut render = HtmlRenderer::new();
+    render.render(events, code.as_bytes(), &|hl| HL_ATTRS[hl.0].as_bytes())
+        .map_err(|e| Error::other(format!("tree-sitter: {e}")))?;
+    Ok(String::from_utf8(render.html).unwrap())
+}
@@ -1,22 +1,45 @@
-use std::{sync::Arc, collections::HashMap};
+use std::{borrow::Cow, sync::Arc, collections::HashMap};
 
 use html::Attributes;
 use regex::Regex;
 use lazy_static::lazy_static;
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
-use crate::html::NO_ATTRS;
+use crate::{html::NO_ATTRS, fence::FenceMeta};
 
+mod cache;
+mod error;
+mod fence;
+#[cfg(feature = "tree-sitter")]
+mod grammars;
 mod html;
 mod starry;
+#[cfg(feature = "syntect")]
+mod syntect_highlight;
 
-pub type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+pub use error::Error;
+pub use starry::Highlighter;
+pub use cache::{CachedHighlighter, default_cache_dir};
+#[cfg(feature = "starry-night")]
+pub use starry::{StarryHighlighter, StarryOptions};
+#[cfg(feature = "syntect")]
+pub use syntect_highlight::SyntectHighlighter;
 
-#[derive(Serialize, Clone)]
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Serialize, Clone, Default)]
 pub struct DocumentMetaData {
     title: Option<String>,
     tags: Vec<String>,
     frontmatter: serde_yaml::Mapping,
+    footnotes: Vec<Footnote>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Footnote {
+    label: String,
+    number: usize,
+    content: String,
 }
 
 fn to_title_case(s: impl AsRef<str>) -> String {
@@ -27,22 +50,62 @@ fn to_title_case(s: impl AsRef<str>) -> String {
     }
 }
 
-#[derive(Clone)]
+/// Rendering options. Start from `MdnyaOptions::default()` and change them with the `with_*` methods,
+/// or deserialize them from a config file. Missing keys take their default value, and unknown keys are an error.
+///
+/// The keys are named after the `with_*` methods, which describe them:
+/// - `close_all_tags`: bool, see [`Self::with_close_all_tags`]
+/// - `wrap_sections`: tag name, see [`Self::with_wrap_sections`]
+/// - `wrap_document`: list of tag names, see [`Self::with_wrap_document`]
+/// - `heading_level`: number, see [`Self::with_heading_level`]
+/// - `header_ids`: bool, see [`Self::with_header_ids`]
+/// - `code_lines`: bool, see [`Self::with_code_lines`]
+/// - `razor`: bool, see [`Self::with_razor`]
+/// - `section_footnotes`: bool, see [`Self::with_section_footnotes`]
+/// - `math`: bool, see [`Self::with_math`]
+///
+/// The highlighter can't be set this way.
+/// ```toml
+/// wrap_sections = "section"
+/// heading_level = 2
+/// code_lines = false
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MdnyaOptions {
     close_all_tags: bool,
     wrap_sections: Option<String>,
     wrap_document: Option<Vec<String>>,
     heading_level: u8,
-    add_header_ids: bool,
-    no_code_lines: bool,
-    highlighter: Option<Arc<dyn starry::Highlighter>>,
+    header_ids: bool,
+    code_lines: bool,
+    #[serde(skip)]
+    highlighter: Option<Arc<dyn Highlighter + Send + Sync>>,
     razor: bool,
+    section_footnotes: bool,
+    math: bool,
+}
+
+// footnotes are numbered in the order they are first referenced
+#[derive(Clone, Default)]
+struct FootnoteState {
+    definitions: HashMap<String, FootnoteDefinition>,
+    order: Vec<String>,
+    ref_counts: HashMap<String, usize>,
+    emitted: usize,
 }
 
 struct MdnyaRenderer<'a> {
     options: MdnyaOptions,
     html: html::HTMLWriter<'a>,
     meta: DocumentMetaData,
+    footnotes: FootnoteState,
+    // link reference definitions, by normalized label
+    definitions: HashMap<String, Definition>,
+    // how many code groups have used each name, for unique ids
+    code_groups: HashMap<String, usize>,
+    // how many admonitions are being rendered, which headings in don't start sections
+    admonition_depth: usize,
 }
 
 impl<'a> MdnyaRenderer<'a> {
@@ -50,11 +113,11 @@ impl<'a> MdnyaRenderer<'a> {
         Self {
             html: html::HTMLWriter::new(output, 4, options.close_all_tags),
             options,
-            meta: DocumentMetaData {
-                title: None,
-                tags: Vec::new(),
-                frontmatter: serde_yaml::Mapping::new(),
-            }
+            meta: DocumentMetaData::default(),
+            footnotes: FootnoteState::default(),
+            definitions: HashMap::new(),
+            code_groups: HashMap::new(),
+            admonition_depth: 0,
         }
     }
 }
@@ -66,6 +129,23 @@ pub fn render_markdown(input: impl AsRef<str>, output: & mut impl std::io::Write
 
 
 
+impl Default for MdnyaOptions {
+    fn default() -> Self {
+        Self {
+            close_all_tags: false,
+            wrap_sections: None,
+            wrap_document: None,
+            heading_level: 1,
+            header_ids: true,
+            code_lines: true,
+            highlighter: None,
+            razor: true,
+            section_footnotes: false,
+            math: false,
+        }
+    }
+}
+
 impl MdnyaOptions {
 
     pub fn new(close_all_tags: bool, wrap_sections: Option<String>, wrap_document: Option<Vec<String>>, heading_level: u8, add_header_ids: bool) -> Self {
@@ -74,25 +154,110 @@ impl MdnyaOptions {
             wrap_sections,
             wrap_document,
             heading_level,
-            add_header_ids,
-            no_code_lines: false,
-            highlighter: None,
-            razor: true,
+            header_ids: add_header_ids,
+            .. Self::default()
+        }
+    }
+
+    /// Write closing tags for `<p>` and `<li>` elements, which are optional in HTML. Default: false
+    pub fn with_close_all_tags(self, close_all_tags: bool) -> Self {
+        Self {
+            close_all_tags,
+            .. self
         }
     }
 
+    /// Surround the content after each heading in this tag, such as `section`. Default: none
+    pub fn with_wrap_sections(self, tag: Option<String>) -> Self {
+        Self {
+            wrap_sections: tag,
+            .. self
+        }
+    }
+
+    /// Surround the whole document in these tags, outermost first, such as `["html", "body"]`. Default: none
+    pub fn with_wrap_document(self, tags: Option<Vec<String>>) -> Self {
+        Self {
+            wrap_document: tags,
+            .. self
+        }
+    }
+
+    /// The level that a `#` heading is written as, so 2 turns `#` into `<h2>`. Default: 1
+    pub fn with_heading_level(self, heading_level: u8) -> Self {
+        Self {
+            heading_level,
+            .. self
+        }
+    }
+
+    /// Add an `id` attribute to headings, made from their text. Default: true
+    pub fn with_header_ids(self, header_ids: bool) -> Self {
+        Self {
+            header_ids,
+            .. self
+        }
+    }
+
+    /// Wrap each line of code blocks in `<span class="code-line">`. Default: true
+    pub fn with_code_lines(self, code_lines: bool) -> Self {
+        Self {
+            code_lines,
+            .. self
+        }
+    }
+
+    /// Pass `@` razor statements and ```` ```@ ```` code blocks through unchanged. Default: true
+    pub fn with_razor(self, razor: bool) -> Self {
+        Self {
+            razor,
+            .. self
+        }
+    }
+
+    /// Emit footnotes at the end of each section instead of the end of the document.
+    /// Only has an effect when sections are wrapped. Default: false
+    pub fn with_section_footnotes(self, section_footnotes: bool) -> Self {
+        Self {
+            section_footnotes,
+            .. self
+        }
+    }
+
+    /// Parse `$inline$` and `$$` block math. The TeX is written escaped, in
+    /// `<span class="math inline">` and `<div class="math display">` elements,
+    /// to be rendered client-side, e.g. by KaTeX or MathJax. Default: false
+    pub fn with_math(self, math: bool) -> Self {
+        Self {
+            math,
+            .. self
+        }
+    }
+
+    /// Highlight code with a new [`StarryHighlighter`], cached in memory and in [`default_cache_dir`].
+    /// This starts node, so to share one between documents, create it and use [`Self::with_highlighter`]
+    #[cfg(feature = "starry-night")]
     pub fn with_starry_night(self) -> Self {
-        static mut STARRY: Option<Arc<starry::StarryHighlighter>> = None;
-        static STARRY_INIT: std::sync::Once = std::sync::Once::new();
+        let mut highlighter = CachedHighlighter::new(StarryHighlighter::new(StarryOptions::default()));
+        if let Some(dir) = default_cache_dir() {
+            highlighter = highlighter.with_disk_cache(dir);
+        }
+        self.with_highlighter(Arc::new(highlighter))
+    }
 
-        STARRY_INIT.call_once(|| {
-            unsafe {
-                STARRY = Some(Arc::new(starry::StarryHighlighter::new(HashMap::new())));
-            }
-        });
+    /// Highlight code blocks with a custom highlighter. Default: none, code is only escaped
+    pub fn with_highlighter(self, highlighter: Arc<dyn Highlighter + Send + Sync>) -> Self {
+        Self {
+            highlighter: Some(highlighter),
+            .. self
+        }
+    }
 
+    /// Highlight code with syntect, which doesn't need nodejs
+    #[cfg(feature = "syntect")]
+    pub fn with_syntect(self) -> Self {
         Self {
-            highlighter: Some(unsafe {STARRY.clone().unwrap()}),
+            highlighter: Some(Arc::new(syntect_highlight::SyntectHighlighter::new())),
             .. self
         }
     }
@@ -103,14 +268,48 @@ use markdown::mdast::*;
 
 impl<'a> MdnyaRenderer<'a> {
 
+    fn parse_markdown(&self, input: &str, frontmatter: bool) -> Result<Vec<Node>> {
+        let mut options = markdown::Options::gfm();
+        options.parse.constructs.frontmatter = frontmatter;
+        if self.options.math {
+            options.parse.constructs.math_flow = true;
+            options.parse.constructs.math_text = true;
+        }
+        let ast = markdown::to_mdast(input, &options.parse)
+            .map_err(|e| Error::Markdown(e.to_string()))?;
+        let Node::Root(Root { children, ..}) = ast else {
+            return Err(Error::UnexpectedNode("document is not a root node".into()));
+        };
+        Ok(children)
+    }
+
+    // parse part of the document on its own, still using the document's link and footnote definitions
+    fn parse_nested(&self, input: &str) -> Result<Vec<Node>> {
+        // references are only made for labels defined in the same parse,
+        // so placeholders for the collected definitions go before it, and are removed after.
+        // they are first so the input can't swallow them, e.g. in an unclosed code block
+        let mut source = String::new();
+        for def in self.footnotes.definitions.values() {
+            source += &format!("[^{}]: .\n\n", def.label.as_deref().unwrap_or(&def.identifier));
+        }
+        for def in self.definitions.values() {
+            source += &format!("[{}]: #\n\n", def.label.as_deref().unwrap_or(&def.identifier));
+        }
+        // ends the last footnote, which would take indented code at the start of the input
+        source += "<!---->\n\n";
+        let prefix_len = source.len();
+        source += input;
+
+        let mut nodes = self.parse_markdown(&source, false)?;
+        nodes.retain(|node| node.position().is_none_or(|pos| pos.start.offset >= prefix_len));
+        Ok(nodes)
+    }
+
     // write to the output and collect metadata
     fn render_document(mut self, input: &str) -> Result<DocumentMetaData> {
         justlogfox::log_trace!("rendering {} bytesof  markdown", (input.len()));
 
-        let mut options = markdown::Options::gfm();
-        options.parse.constructs.frontmatter = true;
-        let ast = markdown::to_mdast(input, &options.parse).unwrap();
-        let Node::Root(Root { children: mut root_nodes, ..}) = ast else { unreachable!() };
+        let mut root_nodes = self.parse_markdown(input, true)?;
 
         if let Some(tags) = &self.options.wrap_document {
             for tag in tags {
@@ -123,18 +322,33 @@ impl<'a> MdnyaRenderer<'a> {
             return Ok(self.meta);
         }; 
 
-        // if the first node is a yaml node, it's the frontmatter
-        if let Node::Yaml(Yaml{value, ..}) = first_node {
-            let fm = serde_yaml::from_str(value).expect("frontmatter is yml map");
-            justlogfox::log_debug!("frontmatter: {:?}", fm);
-            self.meta.frontmatter = fm;
-            root_nodes.remove(0); // skip when rendering HTML
-        } else {
-            justlogfox::log_debug!("no frontmatter");
+        // if the first node is a yaml or toml node, it's the frontmatter
+        match first_node {
+            Node::Yaml(Yaml{value, position}) => {
+                let fm = parse_yaml_frontmatter(value, frontmatter_first_line(position))?;
+                justlogfox::log_debug!("frontmatter: {:?}", fm);
+                self.meta.frontmatter = fm;
+                root_nodes.remove(0); // skip when rendering HTML
+            }
+            Node::Toml(Toml{value, position}) => {
+                let fm = parse_toml_frontmatter(value, frontmatter_first_line(position))?;
+                justlogfox::log_debug!("frontmatter: {:?}", fm);
+                self.meta.frontmatter = fm;
+                root_nodes.remove(0); // skip when rendering HTML
+            }
+            _ => justlogfox::log_debug!("no frontmatter"),
         };
 
+        collect_definitions(&root_nodes, &mut self.definitions, &mut self.footnotes.definitions);
+
         self.render_seq(root_nodes.iter())?;
 
+        if self.options.section_footnotes {
+            self.render_footnotes()?;
+        }
+        self.html.maybe_exit_section()?;
+        self.render_footnotes()?;
+
         if let Some(tags) = &self.options.wrap_document {
             for tag in tags {
                 self.html.end(tag)?;
@@ -169,12 +383,120 @@ impl<'a> MdnyaRenderer<'a> {
                }
             }
 
+            // adjacent code blocks in the same group= are shown as tabs
+            let razor = self.options.razor;
+            if let (Node::Code(first), Some(group)) = (node, code_group(node, razor)) {
+                let mut blocks = vec![first];
+                while let Some(Node::Code(code)) = nodes.next_if(|next| code_group(next, razor) == Some(group)) {
+                    blocks.push(code);
+                }
+                self.render_code_group(group, &blocks)?;
+                continue;
+            }
+
             // default case:
             self.render_node(node)?;
         }
         Ok(())
     }
 
+    // a tab for each code block, labeled with its title or language
+    fn render_code_group(&mut self, group: &str, blocks: &[&Code]) -> Result<()> {
+        let slug = to_fragment(group);
+        let count = self.code_groups.entry(slug.clone()).or_insert(0);
+        *count += 1;
+        let id = match *count {
+            1 => format!("code-{slug}"),
+            n => format!("code-{slug}-{n}"),
+        };
+
+        self.html.start("div", &[("class", Some("code-group"))])?;
+        self.html.start("div", &[("role", Some("tablist"))])?;
+        for (i, block) in blocks.iter().enumerate() {
+            let meta = block.meta.as_deref().unwrap_or_default();
+            let label = fence::value(meta, "title").or_else(|| fence::value(meta, "filename"))
+                .or(block.lang.as_deref())
+                .map_or_else(|| (i + 1).to_string(), str::to_owned);
+            let selected = if i == 0 { "true" } else { "false" };
+            // a button in a form would submit it by default
+            let mut attrs = vec![
+                ("type", Some("button".to_string())),
+                ("role", Some("tab".to_string())),
+                ("id", Some(format!("{id}-tab-{}", i + 1))),
+                ("aria-controls", Some(format!("{id}-panel-{}", i + 1))),
+                ("aria-selected", Some(selected.to_string())),
+            ];
+            if i != 0 {
+                attrs.push(("tabindex", Some("-1".to_string())));
+            }
+            self.tag_wrap_text_inline("button", &attrs, &label)?;
+        }
+        self.html.end("div")?;
+        for (i, block) in blocks.iter().enumerate() {
+            let mut attrs = vec![
+                ("role", Some("tabpanel".to_string())),
+                ("id", Some(format!("{id}-panel-{}", i + 1))),
+                ("aria-labelledby", Some(format!("{id}-tab-{}", i + 1))),
+            ];
+            if i != 0 {
+                attrs.push(("hidden", None));
+            }
+            self.html.start("div", &attrs)?;
+            self.render_codeblock(block)?;
+            self.html.end("div")?;
+        }
+        self.html.end("div")?;
+        Ok(())
+    }
+
+    // a blockquote, or an alert like `> [!NOTE]`
+    fn render_blockquote(&mut self, children: &[Node]) -> Result<()> {
+        lazy_static! {
+            static ref RE_ALERT: Regex = Regex::new(r"(?i)^\[!(note|tip|important|warning|caution)\]([+-]?)[ \t]*").unwrap();
+        }
+        let Some(Node::Paragraph(paragraph @ Paragraph { children: par_nodes, .. })) = children.first()
+            else { return self.tag_wrap("blockquote", NO_ATTRS, children.iter()) };
+        let Some(Node::Text(text_node @ Text { value, .. })) = par_nodes.first()
+            else { return self.tag_wrap("blockquote", NO_ATTRS, children.iter()) };
+        let Some(captures) = RE_ALERT.captures(value)
+            else { return self.tag_wrap("blockquote", NO_ATTRS, children.iter()) };
+
+        let kind = captures[1].to_ascii_lowercase();
+        let collapse = collapse_marker(&captures[2]);
+        // the rest of the first line is a custom title, and the rest of the paragraph starts the body
+        let rest = &value[captures[0].len()..];
+        let (title, rest) = rest.split_once('\n').unwrap_or((rest, ""));
+        let title = if title.trim().is_empty() { to_title_case(&kind) } else { title.trim().to_string() };
+
+        let mut first_nodes = vec![];
+        if !rest.is_empty() {
+            first_nodes.push(Node::Text(Text { value: rest.to_string(), .. text_node.clone() }));
+        }
+        first_nodes.extend(par_nodes.iter().skip(1).cloned());
+        let first = (!first_nodes.is_empty())
+            .then(|| Node::Paragraph(Paragraph { children: first_nodes, .. paragraph.clone() }));
+
+        self.render_admonition(&kind, &title, collapse, first.iter().chain(children.iter().skip(1)))
+    }
+
+    // collapse is None for an admonition that can't be collapsed, or whether it starts open
+    fn render_admonition<'n>(&mut self, kind: &str, title: &str, collapse: Option<bool>, body: impl Iterator<Item=&'n Node>) -> Result<()> {
+        let class_attr = format!("admonition {kind}");
+        let (tag, title_tag) = if collapse.is_some() { ("details", "summary") } else { ("div", "div") };
+        let mut attrs = vec![("class", Some(class_attr.as_str()))];
+        if collapse == Some(true) {
+            attrs.push(("open", None));
+        }
+        self.html.start(tag, &attrs)?;
+        self.tag_wrap_text_inline(title_tag, &[("class", Some("admonition-title"))], title)?;
+        self.admonition_depth += 1;
+        let rendered = self.render_seq(body);
+        self.admonition_depth -= 1;
+        rendered?;
+        self.html.end(tag)?;
+        Ok(())
+    }
+
     fn render_list(&mut self, node: &List) -> Result<()> {
         let (tag, attrs) = match node.start {
             Some(1) => ("ol", vec![]),
@@ -184,7 +506,7 @@ impl<'a> MdnyaRenderer<'a> {
         self.html.start(tag, &attrs)?;
         for li in &node.children {
             let Node::ListItem(ListItem { children: li_nodes, checked, .. }) = li 
-                else { panic!("non-li in list") };
+                else { return Err(Error::UnexpectedNode("non-li in list".into())) };
             self.html.enter_inline()?;
             self.html.start("li", NO_ATTRS)?;
 
@@ -262,23 +584,22 @@ impl<'a> MdnyaRenderer<'a> {
     }
 
     fn render_header(&mut self, node: &Heading) -> Result<()> {
-        if self.options.wrap_sections.is_some() {
+        // sections can't end or start inside an admonition
+        let section_tag = self.options.wrap_sections.clone().filter(|_| self.admonition_depth == 0);
+        if section_tag.is_some() {
+            if self.options.section_footnotes {
+                self.render_footnotes()?;
+            }
             self.html.maybe_exit_section()?;
         }
 
-        lazy_static! {
-            static ref FRAGMENT_REMOVE_RE: Regex = Regex::new(r"[^a-zA-Z0-9-]").unwrap();
-        }
         let mut attrs = vec![];
 
         let Heading { children, depth, .. } = node;
         
-        if self.options.add_header_ids {
-            let fragment = children.iter()
-                           .fold(String::new(), |acc, node| acc + node.to_string().as_str())
-                           .to_ascii_lowercase()
-                           .replace(' ', "-");
-            let fragment = FRAGMENT_REMOVE_RE.replace_all(&fragment, "").to_string();
+        if self.options.header_ids {
+            let fragment = to_fragment(&children.iter()
+                           .fold(String::new(), |acc, node| acc + node.to_string().as_str()));
             
             attrs.push(("id", Some(fragment)));
         }
@@ -289,19 +610,7 @@ impl<'a> MdnyaRenderer<'a> {
 
         // capture title HTML for metadata
         if (self.meta.title.is_none()) && (tag == "h1") && (self.html.indent_level == 0) {
-            let mut tempbuf: Vec<u8> = vec![];
-            {   
-                let mut html = html::HTMLWriter::new(Box::new(&mut tempbuf), 0, true);
-                html.is_inline = true;
-                let mut temp_renderer = MdnyaRenderer {
-                    html,
-                    meta: self.meta.clone(),
-                    options: self.options.clone(),
-                };
-
-                temp_renderer.render_seq(children.iter())?;
-            }
-            let title_html = String::from_utf8(tempbuf).unwrap();
+            let title_html = self.render_to_string(children.iter())?;
             justlogfox::log_debug!("captured title html: {}", title_html);
 
             self.html.enter_inline()?;
@@ -316,7 +625,7 @@ impl<'a> MdnyaRenderer<'a> {
         }
 
 
-        if let Some(section_tag) = &self.options.wrap_sections {
+        if let Some(section_tag) = section_tag {
             self.html.enter_section(section_tag)?;
         }
         Ok(())
@@ -336,41 +645,58 @@ impl<'a> MdnyaRenderer<'a> {
             return Ok(());
         }
 
-        let add_code_lines = 
-            if self.options.no_code_lines {
-                |text: &str| text.trim_end().to_string()
-            } else {
-                |text: &str| {
-                    text.trim_end().lines().map(|line| {
-                        format!("<span class=\"code-line\">{line}</span>")
-                    }).collect::<Vec<_>>().join("\n")
-                }
-            };
-
-        lazy_static! {
-            static ref RE_ADMONITION: Regex = Regex::new(r"\{(\w+)\}").unwrap();
-        }
         let mut attrs = vec![];
+        let mut marks = None;
         let code =
             if let Some(info) = lang {
                 let adm_match = RE_ADMONITION.captures(info);
                 if let Some(captures) = adm_match {
                     let class = &captures[1];
-                    let title = meta.clone().unwrap_or_else(|| to_title_case(class));
-                    let class_attr = format!("admonition {class}");
-                    self.html.start("div", &[("class", Some(&class_attr))])?;
-                    self.tag_wrap_text_inline("div", &[("class", Some("admonition-title"))], &title)?;
-                    self.tag_wrap_text_inline("p", NO_ATTRS, value)?;
-                    self.html.end("div")?;
+                    let mut collapse = collapse_marker(&captures[2]);
+                    // `collapsible` before the title starts closed, unless marked with +
+                    let mut title = meta.as_deref().map(str::trim);
+                    if let Some(rest) = title.and_then(|t| t.strip_prefix("collapsible")) {
+                        if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                            collapse = collapse.or(Some(false));
+                            title = Some(rest.trim()).filter(|t| !t.is_empty());
+                        }
+                    }
+                    let title = title.map_or_else(|| to_title_case(class), str::to_string);
+                    // the body is a document of its own, which may have more admonitions
+                    let body = self.parse_nested(value)?;
+                    collect_definitions(&body, &mut self.definitions, &mut self.footnotes.definitions);
+                    self.render_admonition(class, &title, collapse, body.iter())?;
                     return Ok(());
                 }
 
                 attrs.push(("data-lang", Some(info)));
-                
-                if let Some(highlighter) = &self.options.highlighter {
-                    highlighter.highlight(info, value)?
-                } else {
-                    html_escape::encode_text(&value).to_string()
+
+                // `diff`, or `diff-rust` for a diff highlighted as rust without its markers
+                let diff_lang = info.strip_prefix("diff-");
+                if info == "diff" || diff_lang.is_some() {
+                    marks = Some(fence::diff_marks(value));
+                }
+                let (info, value) = match (diff_lang, &marks) {
+                    (Some(inner), Some(marks)) => (inner, Cow::Owned(value.lines().zip(marks)
+                        .map(|(line, mark)| &line[mark.len()..])
+                        .collect::<Vec<_>>().join("\n"))),
+                    _ => (info, Cow::Borrowed(value.as_str())),
+                };
+
+                let code =
+                    if let Some(highlighter) = &self.options.highlighter {
+                        highlighter.highlight(info, &value, meta.as_deref())
+                            .map_err(|source| Error::Highlight { lang: info.to_string(), source })?
+                    } else {
+                        html_escape::encode_text(&*value).to_string()
+                    };
+
+                match (diff_lang, &marks) {
+                    // put the markers back in front of the highlighted lines
+                    (Some(_), Some(marks)) => fence::balanced_lines(code.trim_end()).iter().enumerate()
+                        .map(|(i, line)| format!("{}{line}", marks.get(i).unwrap_or(&"")))
+                        .collect::<Vec<_>>().join("\n"),
+                    _ => code,
                 }
 
 
@@ -379,8 +705,34 @@ impl<'a> MdnyaRenderer<'a> {
             };
         
 
-        let code = add_code_lines(&code);
+        let fence = meta.as_deref().map(FenceMeta::parse).unwrap_or_default();
+        let code =
+            if !self.options.code_lines {
+                code.trim_end().to_string()
+            } else {
+                // spans are split at line ends, so each line can be wrapped
+                fence::balanced_lines(code.trim_end()).iter().enumerate().map(|(i, line)| {
+                    let number = fence.start_line.unwrap_or(1) + i;
+                    let mut class = if fence.is_highlighted(number) { " highlighted" } else { "" }.to_string();
+                    match marks.as_ref().and_then(|marks| marks.get(i)) {
+                        Some(&"+") => class.push_str(" inserted"),
+                        Some(&"-") => class.push_str(" deleted"),
+                        _ => (),
+                    }
+                    match fence.start_line {
+                        Some(_) => format!("<span class=\"code-line{class}\" data-line=\"{number}\">{line}</span>"),
+                        None => format!("<span class=\"code-line{class}\">{line}</span>"),
+                    }
+                }).collect::<Vec<_>>().join("\n")
+            };
+        attrs.extend(fence.data.iter().map(|(key, value)| (key.as_str(), value.as_deref())));
 
+        // in a group, the title labels the tab instead
+        let title = fence.title.as_ref().filter(|_| fence.group.is_none());
+        if let Some(title) = title {
+            self.html.start("figure", NO_ATTRS)?;
+            self.tag_wrap_text_inline("figcaption", NO_ATTRS, title)?;
+        }
         self.html.enter_inline()?;
         self.html.start("pre", &attrs)?;
         self.html.start("code", NO_ATTRS)?;
@@ -388,6 +740,9 @@ impl<'a> MdnyaRenderer<'a> {
         self.html.end("code")?;
         self.html.end("pre")?;
         self.html.exit_inline()?;
+        if title.is_some() {
+            self.html.end("figure")?;
+        }
         Ok(())
     }
 
@@ -404,7 +759,7 @@ impl<'a> MdnyaRenderer<'a> {
 
             // simple
             Node::BlockQuote(BlockQuote { children, .. }) => 
-                self.tag_wrap("blockquote", NO_ATTRS, children.iter())?,
+                self.render_blockquote(children)?,
             Node::Emphasis(Emphasis { children, .. }) => 
                 self.tag_wrap("em", NO_ATTRS, children.iter())?,
             Node::Strong(Strong { children, .. }) => 
@@ -413,6 +768,10 @@ impl<'a> MdnyaRenderer<'a> {
                 self.tag_wrap("del", NO_ATTRS, children.iter())?,
             Node::InlineCode(InlineCode { value, .. }) => 
                 self.tag_wrap_text("code", NO_ATTRS, value)?,
+            Node::InlineMath(InlineMath { value, .. }) => 
+                self.tag_wrap_text("span", &[("class", Some("math inline"))], value)?,
+            Node::Math(Math { value, .. }) => 
+                self.tag_wrap_text_inline("div", &[("class", Some("math display"))], value)?,
             Node::Link(Link { url, children, .. }) => 
                 self.tag_wrap("a", &[("href", Some(&url))], children.iter())?,
 
@@ -422,38 +781,186 @@ impl<'a> MdnyaRenderer<'a> {
             Node::Paragraph(par) => self.render_paragraph(par)?,
             Node::Heading(heading) => self.render_header(heading)?,
             Node::Code(codeblock) => self.render_codeblock(codeblock)?,
+            Node::FootnoteReference(FootnoteReference { identifier, label, .. }) =>
+                self.render_footnote_reference(identifier, label.as_deref())?,
+
+            Node::LinkReference(link_ref) => self.render_link_reference(link_ref)?,
+            Node::ImageReference(image_ref) => self.render_image_reference(image_ref)?,
+
+            // collected before rendering
+            Node::Definition(_) |
+            Node::FootnoteDefinition(_) => (),
 
             // Should be handled by other cases
             Node::Root(_) |
             Node::Yaml(_) |
+            Node::Toml(_) |
             Node::ListItem(_) |
             Node::Table(_) |
             Node::TableRow(_) |
             Node::TableCell(_)
-                => panic!("unexpected node"),
-
-            // TODO
-            Node::FootnoteDefinition(_) |
-            Node::FootnoteReference(_) |
-            Node::Definition(_) |
-            Node::ImageReference(_) |
-            Node::LinkReference(_) |
-            Node::Toml(_) 
-                => todo!("{:?}", node),
+                => return Err(Error::UnexpectedNode(format!("{:?} outside of its parent", node))),
 
             // not enabled
             Node::MdxJsxFlowElement(_) |
             Node::MdxJsxTextElement(_) |
             Node::MdxFlowExpression(_) |
             Node::MdxTextExpression(_) |
-            Node::MdxjsEsm(_) |
-            Node::InlineMath(_) |
-            Node::Math(_)
+            Node::MdxjsEsm(_)
                 => unreachable!()
         };
         Ok(())
     }
 
+    fn render_link_reference(&mut self, node: &LinkReference) -> Result<()> {
+        let LinkReference { children, identifier, label, reference_kind, .. } = node;
+
+        let Some(Definition { url, title, .. }) = self.definitions.get(&normalize_label(identifier)).cloned() else {
+            justlogfox::log_debug!("undefined link reference: {}", identifier);
+            self.html.write_text("[")?;
+            self.render_seq(children.iter())?;
+            self.html.write_text("]")?;
+            self.html.write_text(reference_suffix(reference_kind, label.as_deref().unwrap_or(identifier)))?;
+            return Ok(());
+        };
+
+        let mut attrs = vec![("href", Some(url.as_str()))];
+        if let Some(title) = &title {
+            attrs.push(("title", Some(title.as_str())));
+        }
+        self.tag_wrap("a", &attrs, children.iter())
+    }
+
+    fn render_image_reference(&mut self, node: &ImageReference) -> Result<()> {
+        let ImageReference { alt, identifier, label, reference_kind, .. } = node;
+
+        let Some(Definition { url, title, .. }) = self.definitions.get(&normalize_label(identifier)).cloned() else {
+            justlogfox::log_debug!("undefined image reference: {}", identifier);
+            self.html.write_text(format!("![{alt}]"))?;
+            self.html.write_text(reference_suffix(reference_kind, label.as_deref().unwrap_or(identifier)))?;
+            return Ok(());
+        };
+
+        let mut attrs = vec![("src", Some(url.as_str())), ("alt", Some(alt.as_str()))];
+        if let Some(title) = &title {
+            attrs.push(("title", Some(title.as_str())));
+        }
+        self.html.void_tag("img", &attrs, false)?;
+        Ok(())
+    }
+
+    fn render_footnote_reference(&mut self, identifier: &str, label: Option<&str>) -> Result<()> {
+        if !self.footnotes.definitions.contains_key(identifier) {
+            justlogfox::log_warn!("undefined footnote: {}", identifier);
+            self.html.write_text(format!("[^{}]", label.unwrap_or(identifier)))?;
+            return Ok(());
+        }
+
+        let number = match self.footnotes.order.iter().position(|id| id == identifier) {
+            Some(i) => i + 1,
+            None => {
+                self.footnotes.order.push(identifier.to_string());
+                self.footnotes.order.len()
+            }
+        };
+        let ref_count = self.footnotes.ref_counts.entry(identifier.to_string()).or_insert(0);
+        *ref_count += 1;
+
+        let ref_id = footnote_ref_id(number, *ref_count);
+        let href = format!("#fn-{number}");
+
+        self.html.start("sup", &[("class", Some("footnote-ref"))])?;
+        self.html.start("a", &[("href", Some(&href)), ("id", Some(&ref_id))])?;
+        self.html.write_html(number.to_string())?;
+        self.html.end("a")?;
+        self.html.end("sup")?;
+        Ok(())
+    }
+
+    // write any referenced footnotes which have not been written yet
+    fn render_footnotes(&mut self) -> Result<()> {
+        if self.footnotes.emitted == self.footnotes.order.len() {
+            return Ok(());
+        }
+
+        let first_number = self.footnotes.emitted + 1;
+        let mut attrs = vec![];
+        if first_number != 1 {
+            attrs.push(("start", Some(first_number.to_string())));
+        }
+
+        self.html.start("section", &[("class", Some("footnotes"))])?;
+        self.html.start("ol", &attrs)?;
+
+        // footnotes may reference other footnotes, growing the order while rendering
+        while self.footnotes.emitted < self.footnotes.order.len() {
+            let identifier = self.footnotes.order[self.footnotes.emitted].clone();
+            self.footnotes.emitted += 1;
+            let number = self.footnotes.emitted;
+
+            let FootnoteDefinition { children, label, .. } = self.footnotes.definitions[&identifier].clone();
+
+            let content = 
+                if let [Node::Paragraph(Paragraph { children: par_nodes, .. })] = children.as_slice() {
+                    self.render_to_string(par_nodes.iter())?
+                } else {
+                    self.render_to_string(children.iter())?
+                };
+
+            let li_id = format!("fn-{number}");
+            self.html.enter_inline()?;
+            self.html.start("li", &[("id", Some(&li_id))])?;
+            self.html.write_html(&content)?;
+            for i in 1..=self.footnotes.ref_counts[&identifier] {
+                let href = format!("#{}", footnote_ref_id(number, i));
+                self.html.write_html(" ")?;
+                self.html.start("a", &[("href", Some(href.as_str())), ("class", Some("footnote-backref"))])?;
+                self.html.write_html("↩")?;
+                self.html.end("a")?;
+            }
+            self.html.end("li")?;
+            self.html.exit_inline()?;
+
+            self.meta.footnotes.push(Footnote {
+                label: label.unwrap_or(identifier),
+                number,
+                content,
+            });
+        }
+
+        self.html.end("ol")?;
+        self.html.end("section")?;
+        Ok(())
+    }
+
+    // render nodes inline to a string, keeping collected metadata
+    fn render_to_string<'n, Nodes>(&mut self, nodes: Nodes) -> Result<String>
+        where Nodes: Iterator<Item=&'n Node>,
+    {
+        let mut tempbuf: Vec<u8> = vec![];
+        {
+            let mut html = html::HTMLWriter::new(Box::new(&mut tempbuf), 0, true);
+            html.is_inline = true;
+            let mut temp_renderer = MdnyaRenderer {
+                html,
+                meta: std::mem::take(&mut self.meta),
+                options: self.options.clone(),
+                footnotes: std::mem::take(&mut self.footnotes),
+                definitions: std::mem::take(&mut self.definitions),
+                code_groups: std::mem::take(&mut self.code_groups),
+                admonition_depth: self.admonition_depth,
+            };
+
+            temp_renderer.render_seq(nodes)?;
+
+            self.meta = temp_renderer.meta;
+            self.footnotes = temp_renderer.footnotes;
+            self.definitions = temp_renderer.definitions;
+            self.code_groups = temp_renderer.code_groups;
+        }
+        Ok(String::from_utf8(tempbuf).unwrap())
+    }
+
     fn tag_wrap<'n, Nodes>(&mut self, tag: &str, attrs: impl Attributes, nodes: Nodes) -> Result<()> 
         where Nodes: Iterator<Item=&'n Node>,
     {
@@ -502,16 +1009,21 @@ impl<'a> MdnyaRenderer<'a> {
         }
 
         // flatten rows into cells
-        let cells = rows.iter().flat_map(|row| match row { 
-            Node::TableRow(TableRow { children: cells, .. }) => cells,
-            _ => panic!("non-row in table"),
-        }).map(|cell| match cell {
-            Node::TableCell(TableCell { children, .. }) => children,
-            _ => panic!("non-cell in table row"),
-        }).zip(align_attrs).collect::<Vec<_>>();
+        let mut cells = vec![];
+        for row in rows {
+            let Node::TableRow(TableRow { children: row_cells, .. }) = row
+                else { return Err(Error::UnexpectedNode("non-row in table".into())) };
+            for cell in row_cells {
+                let Node::TableCell(TableCell { children, .. }) = cell
+                    else { return Err(Error::UnexpectedNode("non-cell in table row".into())) };
+                cells.push(children);
+            }
+        }
+        let cells = cells.into_iter().zip(align_attrs).collect::<Vec<_>>();
         let mut cells = cells.chunks(align.len());
 
-        let header_cells = cells.next().unwrap();
+        let Some(header_cells) = cells.next()
+            else { return Err(Error::UnexpectedNode("table without header row".into())) };
 
         self.html.start("thead", NO_ATTRS)?;
         for (nodes, attrs) in header_cells {
@@ -532,4 +1044,132 @@ impl<'a> MdnyaRenderer<'a> {
         self.html.end("table")?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// `+` for an admonition that starts open, `-` for closed
+fn collapse_marker(marker: &str) -> Option<bool> {
+    match marker {
+        "+" => Some(true),
+        "-" => Some(false),
+        _ => None,
+    }
+}
+
+lazy_static! {
+    // the language of an admonition code block, like `{note}` or `{note-}`
+    static ref RE_ADMONITION: Regex = Regex::new(r"\{(\w+)([+-]?)\}").unwrap();
+}
+
+// the group= of a code block,
+// unless it is an admonition or razor, which can't be shown as a tab
+fn code_group(node: &Node, razor: bool) -> Option<&str> {
+    match node {
+        Node::Code(Code { lang: Some(lang), .. }) if (razor && lang == "@") || RE_ADMONITION.is_match(lang) => None,
+        Node::Code(Code { meta: Some(meta), .. }) => fence::value(meta, "group"),
+        _ => None,
+    }
+}
+
+// footnotes are identified by number, since labels may not be usable as ids
+fn footnote_ref_id(number: usize, n: usize) -> String {
+    if n == 1 {
+        format!("fnref-{number}")
+    } else {
+        format!("fnref-{number}-{n}")
+    }
+}
+
+// usable as an id, for linking to with #: lowercase, with dashes for spaces, and only letters, numbers and dashes
+fn to_fragment(text: &str) -> String {
+    lazy_static! {
+        static ref FRAGMENT_REMOVE_RE: Regex = Regex::new(r"[^a-zA-Z0-9-]").unwrap();
+    }
+    FRAGMENT_REMOVE_RE.replace_all(&text.to_ascii_lowercase().replace(' ', "-"), "").to_string()
+}
+
+// the frontmatter content starts on the line after the opening fence
+fn frontmatter_first_line(position: &Option<markdown::unist::Position>) -> usize {
+    position.as_ref().map(|p| p.start.line + 1).unwrap_or(2)
+}
+
+// 1-based line and column of a byte offset
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rfind('\n').map(|i| offset - i).unwrap_or(offset + 1);
+    (line, column)
+}
+
+fn parse_yaml_frontmatter(value: &str, first_line: usize) -> Result<serde_yaml::Mapping> {
+    if value.trim().is_empty() {
+        return Ok(serde_yaml::Mapping::new());
+    }
+    serde_yaml::from_str(value).map_err(|e| {
+        let (line, column) = e.location().map(|loc| (loc.line(), loc.column())).unwrap_or((1, 1));
+        Error::Frontmatter { message: e.to_string(), line: first_line + line - 1, column }
+    })
+}
+
+fn parse_toml_frontmatter(value: &str, first_line: usize) -> Result<serde_yaml::Mapping> {
+    let table: toml::Table = toml::from_str(value).map_err(|e| {
+        let (line, column) = e.span().map(|span| line_column(value, span.start)).unwrap_or((1, 1));
+        let message = match e.message() {
+            "" => e.to_string(),
+            message => message.to_string(),
+        };
+        Error::Frontmatter { message, line: first_line + line - 1, column }
+    })?;
+    Ok(toml_table_to_yaml(table))
+}
+
+// toml frontmatter is stored the same way as yaml, so metadata looks the same
+fn toml_table_to_yaml(table: toml::Table) -> serde_yaml::Mapping {
+    table.into_iter()
+        .map(|(k, v)| (serde_yaml::Value::String(k), toml_to_yaml(v)))
+        .collect()
+}
+
+fn toml_to_yaml(value: toml::Value) -> serde_yaml::Value {
+    use serde_yaml::Value as Yaml;
+    match value {
+        toml::Value::String(s) => Yaml::String(s),
+        toml::Value::Integer(i) => Yaml::Number(i.into()),
+        toml::Value::Float(f) => Yaml::Number(f.into()),
+        toml::Value::Boolean(b) => Yaml::Bool(b),
+        toml::Value::Datetime(dt) => Yaml::String(dt.to_string()),
+        toml::Value::Array(arr) => Yaml::Sequence(arr.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(table) => Yaml::Mapping(toml_table_to_yaml(table)),
+    }
+}
+
+// labels match case-insensitively, with whitespace collapsed
+fn normalize_label(label: &str) -> String {
+    label.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+// the literal text after the brackets of an unresolved reference
+fn reference_suffix(kind: &ReferenceKind, label: &str) -> String {
+    match kind {
+        ReferenceKind::Full => format!("[{label}]"),
+        ReferenceKind::Collapsed => "[]".to_string(),
+        ReferenceKind::Shortcut => String::new(),
+    }
+}
+
+// the first definition of a label wins
+fn collect_definitions(nodes: &[Node], definitions: &mut HashMap<String, Definition>, footnotes: &mut HashMap<String, FootnoteDefinition>) {
+    for node in nodes {
+        match node {
+            Node::Definition(def) => {
+                definitions.entry(normalize_label(&def.identifier)).or_insert_with(|| def.clone());
+            }
+            Node::FootnoteDefinition(def) => {
+                footnotes.entry(def.identifier.clone()).or_insert_with(|| def.clone());
+            }
+            _ => ()
+        }
+        if let Some(children) = node.children() {
+            collect_definitions(children, definitions, footnotes);
+        }
+    }
+}
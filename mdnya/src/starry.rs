@@ -1,179 +1,354 @@
+use std::io::Result;
+
+#[cfg(feature = "starry-night")]
 use std::{
-    path::PathBuf,
-    process::{Child, Stdio}, 
-    io::{Read, Write, Result, BufRead, BufReader},
-    sync::Mutex
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    io::{Error, ErrorKind, Write, BufRead, BufReader},
+    sync::{Mutex, mpsc::{self, Receiver, RecvTimeoutError}},
+    time::Duration,
 };
 
-const INDEXJS_SRC: &str = include_str!("../../dist/bundle.cjs");
+#[cfg(feature = "starry-night")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "starry-night")]
+pub(crate) const INDEXJS_SRC: &str = include_str!("../../dist/bundle.cjs");
 
+/// Where mdnya keeps the Starry Night bundle
+#[cfg(feature = "starry-night")]
+fn data_dir() -> PathBuf {
+    dirs::data_local_dir().unwrap().join(".mdnya")
+}
+
+#[cfg(feature = "starry-night")]
 fn ensure_indexjs() -> Result<PathBuf> {
-    let indexjs = dirs::data_local_dir().unwrap().join(".mdnya").join("bundle.cjs");
-    if !indexjs.exists() {
+    let indexjs = data_dir().join("bundle.cjs");
+    // a bundle from another version of mdnya may speak a different protocol
+    if std::fs::read_to_string(&indexjs).ok().as_deref() != Some(INDEXJS_SRC) {
         std::fs::create_dir_all(indexjs.parent().unwrap())?;
         std::fs::write(&indexjs, INDEXJS_SRC)?;
     }
     Ok(indexjs)
 }
 
+/// Turns the code in a fenced code block into HTML.
+/// Implement this to use another highlighter, with [`crate::MdnyaOptions::with_highlighter`].
 pub trait Highlighter {
-    fn highlight(&self, lang: &str, code: &str) -> Result<String>;
+    /// Highlight `code`, which is in the language `lang`.
+    /// `meta` is the rest of the info string after the language, e.g. `{linenos=true}`.
+    /// The result is put inside `<pre><code>`, so it must be escaped HTML.
+    fn highlight(&self, lang: &str, code: &str, meta: Option<&str>) -> Result<String>;
+
+    /// Changes when the settings of the highlighter would change its output for the same code,
+    /// so that output cached with other settings isn't used
+    fn fingerprint(&self) -> u64 {
+        0
+    }
 }
 
-pub struct StarryHighlighter<'a> {
-    node: Mutex<Child>,
-    init: std::sync::Once,
-    rename_langs: std::collections::HashMap<&'a str, &'a str>,
+/// One line of JSON sent to index.js
+#[cfg(feature = "starry-night")]
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    lang: &'a str,
+    code: &'a str,
+}
 
+/// One line of JSON from index.js, with exactly one of `html` or `error`
+#[cfg(feature = "starry-night")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Response {
+    id: Option<u64>,
+    html: Option<String>,
+    error: Option<ResponseError>,
 }
 
-impl<'a> StarryHighlighter<'a> {
+#[cfg(feature = "starry-night")]
+#[derive(Deserialize)]
+struct ResponseError {
+    kind: String,
+    message: String,
+}
 
-    fn wait_for_starry(&self) {
-        self.init.call_once(|| {
-            let start = std::time::Instant::now();
-            justlogfox::log_info!("waiting for starry night");
-            {
-                let mut buf = [0u8; 6];
-                let mut node = self.node.lock().unwrap();
-                node.stdout.as_mut().unwrap().read_exact(&mut buf).unwrap();
-                assert_eq!(&buf, b"ready\n");
-            }
-            let elapsed = start.elapsed();
-            justlogfox::log_info!("starry night loaded :D\ntook: {}ms", (elapsed.as_millis()));
-        });
+#[cfg(feature = "starry-night")]
+#[derive(Deserialize)]
+struct Ready {
+    ready: bool,
+}
+
+#[cfg(feature = "starry-night")]
+fn protocol_error(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("starry night: {}", message.into()))
+}
+
+/// How long to wait for Starry Night to load its grammars
+#[cfg(feature = "starry-night")]
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for one code block, before giving up on it and restarting node
+#[cfg(feature = "starry-night")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings for a [`StarryHighlighter`]
+#[cfg(feature = "starry-night")]
+#[derive(Clone, Debug)]
+pub struct StarryOptions {
+    aliases: HashMap<String, String>,
+    class_prefix: String,
+    timeout: Duration,
+}
+
+#[cfg(feature = "starry-night")]
+impl Default for StarryOptions {
+    fn default() -> Self {
+        Self {
+            aliases: HashMap::from([
+                ("md".to_owned(), "markdown".to_owned()),
+                ("sh".to_owned(), "bash".to_owned()),
+            ]),
+            class_prefix: "pl-".to_owned(),
+            timeout: REQUEST_TIMEOUT,
+        }
     }
+}
 
-    pub fn new(language_aliases: impl Into<std::collections::HashMap<&'a str, &'a str>>) -> Self {
-        let indexjs = ensure_indexjs();
-        let Ok(indexjs) = indexjs else {
-            justlogfox::log_error!("failed to setup starry night js file");
-            std::process::exit(1);
-        };
-        justlogfox::log_info!("index.js bundled at {:?}", indexjs);
+#[cfg(feature = "starry-night")]
+impl StarryOptions {
+    /// Highlight code blocks marked `alias` as the language `lang`.
+    /// Starry Night already knows the common names of most languages.
+    pub fn with_alias(mut self, alias: impl Into<String>, lang: impl Into<String>) -> Self {
+        self.aliases.insert(alias.into(), lang.into());
+        self
+    }
+
+    /// Start the classes of highlighted spans with this instead of `pl-`,
+    /// for themes written for another prefix. Default: `pl-`
+    pub fn with_class_prefix(self, class_prefix: impl Into<String>) -> Self {
+        Self {
+            class_prefix: class_prefix.into(),
+            .. self
+        }
+    }
+
+    /// Give up on a code block, write it without highlighting, and restart node after this long.
+    /// Default: 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout,
+            .. self
+        }
+    }
+}
+
+/// Settings passed to index.js when it starts
+#[cfg(feature = "starry-night")]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodeOptions<'a> {
+    class_prefix: &'a str,
+}
+
+/// The node process and its pipes, which are only used while locked
+#[cfg(feature = "starry-night")]
+struct Node {
+    child: Child,
+    stdin: ChildStdin,
+    // lines from stdout, read on another thread so that reads can time out
+    lines: Receiver<Result<String>>,
+    ready: bool,
+    next_id: u64,
+}
+
+#[cfg(feature = "starry-night")]
+impl Node {
+    fn spawn(indexjs: &Path, options: &NodeOptions) -> Result<Node> {
         justlogfox::log_info!("starting node");
-        let node = std::process::Command::new("node")
+        let mut child = Command::new("node")
             .arg(indexjs)
+            .arg(serde_json::to_string(options)?)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::inherit())
-            .spawn().expect("node not found");
-        let mut rename_langs = language_aliases.into();
-        rename_langs.insert("md", "markdown");
-        rename_langs.insert("sh", "bash");
-        Self {
-            node: Mutex::new(node),
-            rename_langs,
-            init: std::sync::Once::new(),
-        }
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (send, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in stdout.lines() {
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Node { child, stdin, lines, ready: false, next_id: 0 })
     }
 
-}
+    fn read_line(&mut self, timeout: Duration) -> Result<String> {
+        match self.lines.recv_timeout(timeout) {
+            Ok(line) => {
+                let line = line?;
+                justlogfox::log_trace!("read from node\n{:?}", (&line));
+                Ok(line)
+            }
+            Err(RecvTimeoutError::Timeout) => Err(Error::new(ErrorKind::TimedOut,
+                format!("starry night did not respond within {}ms", timeout.as_millis()))),
+            Err(RecvTimeoutError::Disconnected) =>
+                Err(Error::new(ErrorKind::UnexpectedEof, "starry night exited")),
+        }
+    }
 
-const HL_NAMES: &[&str] = &[
-    "attribute",
-    "constant",
-    "function.builtin",
-    "function",
-    "keyword",
-    "operator",
-    "property",
-    "punctuation",
-    "punctuation.bracket",
-    "punctuation.delimiter",
-    "string",
-    "string.special",
-    "tag",
-    "type",
-    "type.builtin",
-    "variable",
-    "variable.builtin",
-    "variable.parameter",
-    "number",
-    "comment",
-];
-
-const HL_CLASSES: &[&str] = &[
-    "attribute",
-    "constant",
-    "function-builtin",
-    "function",
-    "keyword",
-    "operator",
-    "property",
-    "punctuation",
-    "punctuation-bracket",
-    "punctuation-delimiter",
-    "string",
-    "string-special",
-    "tag",
-    "type",
-    "type-builtin",
-    "variable",
-    "variable-builtin",
-    "variable-parameter",
-    "number",
-    "comment",
-];
-
-impl<'a> Highlighter for StarryHighlighter<'a> {
-    fn highlight(&self, lang: &str, code: &str) -> Result<String> {
-
-        if ["C#", "csharp", "cs"].contains(&lang) {
-            use tree_sitter_highlight::*;
-            use tree_sitter_c_sharp::*;
-
-            let cs = language();
-            let mut cfg = HighlightConfiguration::new(
-                        cs, HIGHLIGHT_QUERY, "", "")
-                      .expect("upstream query is valid");
-            cfg.configure(HL_NAMES);
-            let mut hl = Highlighter::new();
-            let events = hl.highlight(&cfg, code.as_bytes(), None, |_| None).unwrap();
-            let mut render = HtmlRenderer::new();
-            render.render(events, code.as_bytes(), &|hl| HL_CLASSES[hl.0].as_bytes()).unwrap();
-            return Ok(String::from_utf8(render.html).unwrap());
+    fn wait_for_starry(&mut self) -> Result<()> {
+        let start = std::time::Instant::now();
+        justlogfox::log_info!("waiting for starry night");
+        let line = self.read_line(READY_TIMEOUT)?;
+        match serde_json::from_str::<Ready>(&line) {
+            Ok(Ready { ready: true }) => (),
+            _ => return Err(protocol_error(format!("expected ready message, got {line:?}"))),
         }
+        self.ready = true;
+        let elapsed = start.elapsed();
+        justlogfox::log_info!("starry night loaded :D\ntook: {}ms", (elapsed.as_millis()));
+        Ok(())
+    }
 
+    /// Send one request and read its response.
+    /// The outer error means the process can't be used anymore, the inner one is for this code block only.
+    fn request(&mut self, lang: &str, code: &str, timeout: Duration)
+        -> Result<core::result::Result<String, ResponseError>>
+    {
+        if !self.ready {
+            self.wait_for_starry()?;
+        }
 
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request = serde_json::to_string(&Request { id, lang, code })?;
+        request.push('\n');
+        self.stdin.write_all(request.as_bytes())?;
+        self.stdin.flush()?;
 
+        let line = self.read_line(timeout)?;
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|e| protocol_error(format!("invalid response: {e}")))?;
+        if response.id != Some(id) {
+            return Err(protocol_error(format!("expected response {id}, got {:?}", response.id)));
+        }
+        match response {
+            Response { html: Some(html), error: None, .. } => Ok(Ok(html)),
+            Response { html: None, error: Some(error), .. } => Ok(Err(error)),
+            _ => Err(protocol_error(format!("response {id} must have one of html or error"))),
+        }
+    }
+}
 
-        justlogfox::log_trace!("try highlight language: {} ", lang);
-        self.wait_for_starry();
+#[cfg(feature = "starry-night")]
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
 
-        let lang = self.rename_langs.get(lang).unwrap_or(&lang);
+/// Highlights code with Starry Night, in node processes that are killed when this is dropped.
+/// Create one and share it between documents with [`crate::MdnyaOptions::with_highlighter`],
+/// since starting node takes a while.
+#[cfg(feature = "starry-night")]
+pub struct StarryHighlighter {
+    indexjs: Option<PathBuf>,
+    options: StarryOptions,
+    // node processes that aren't highlighting anything. Another is started when
+    // all of them are busy, so there is one for each thread highlighting at once
+    idle: Mutex<Vec<Node>>,
+}
 
-        let mut node = self.node.lock().unwrap();
-        {
-            let node_stdin = node.stdin.as_mut().unwrap();
+#[cfg(feature = "starry-night")]
+impl StarryHighlighter {
 
-            writeln!(node_stdin, "{}", lang)?; 
-            for line in code.lines() { // write code to highlight
-                writeln!(node_stdin, "\t{}", line)?;
+    /// Start loading Starry Night. Code blocks only wait for it once they need it
+    pub fn new(options: StarryOptions) -> Self {
+        let indexjs = match ensure_indexjs() {
+            Ok(indexjs) => {
+                justlogfox::log_info!("index.js bundled at {:?}", indexjs);
+                Some(indexjs)
+            }
+            Err(e) => {
+                justlogfox::log_error!("failed to setup starry night js file: {}", e);
+                None
             }
-            writeln!(node_stdin)?;
+        };
+        let highlighter = Self {
+            indexjs,
+            options,
+            idle: Mutex::new(vec![]),
+        };
+        if let Some(node) = highlighter.spawn() {
+            highlighter.idle.lock().unwrap().push(node);
         }
+        highlighter
+    }
+
+    fn spawn(&self) -> Option<Node> {
+        let indexjs = self.indexjs.as_ref()?;
+        Node::spawn(indexjs, &NodeOptions { class_prefix: &self.options.class_prefix })
+            .map_err(|e| justlogfox::log_warn!("failed to start node: {}", e))
+            .ok()
+    }
 
-        let node_stdout = node.stdout.as_mut().unwrap();
-        let mut node_stdout_buf = BufReader::new(node_stdout);
-        let mut hl = String::new();
-        loop { // read back highlighted code
-            let mut line = String::new();
-            let n = node_stdout_buf.read_line(&mut line)?;
-            justlogfox::log_trace!("read {} bytes from node\n{:?}", n, (&line));
-            
-            if n == 0 || line == "\x04\n" { // EOT or EOF
-                break;
-            } 
-            hl = hl + &line;
+    /// An idle node process that is still running, or a new one
+    fn take_node(&self) -> Option<Node> {
+        while let Some(mut node) = self.idle.lock().unwrap().pop() {
+            match node.child.try_wait() {
+                Ok(Some(status)) => justlogfox::log_warn!("starry night exited with {}, restarting it", status),
+                _ => return Some(node),
+            }
         }
-        Ok(hl)
+        self.spawn()
     }
+
 }
 
-impl<'a> Drop for StarryHighlighter<'a> {
-    fn drop(&mut self) {
-        self.node.lock().unwrap().kill().unwrap();
+#[cfg(feature = "starry-night")]
+impl Highlighter for StarryHighlighter {
+    fn highlight(&self, lang: &str, code: &str, _meta: Option<&str>) -> Result<String> {
+
+        #[cfg(feature = "tree-sitter")]
+        if let Some(highlighted) = crate::grammars::highlight(lang, code) {
+            return highlighted;
+        }
+
+        justlogfox::log_trace!("try highlight language: {} ", lang);
+        let starry_lang = self.options.aliases.get(lang).map_or(lang, String::as_str);
+
+        if let Some(mut node) = self.take_node() {
+            let response = node.request(starry_lang, code, self.options.timeout);
+            if response.is_ok() {
+                self.idle.lock().unwrap().push(node);
+            }
+            match response {
+                Ok(Ok(html)) => return Ok(html),
+                Ok(Err(error)) if error.kind == "unknown-language" =>
+                    justlogfox::log_debug!("starry night has no grammar for {}", lang),
+                Ok(Err(error)) =>
+                    justlogfox::log_warn!("failed to highlight {}: {}", lang, (error.message)),
+                // the process was dropped, which kills it, and the next code block gets a new one
+                Err(e) =>
+                    justlogfox::log_warn!("failed to highlight {}, restarting starry night: {}", lang, e),
+            }
+        }
+        Ok(html_escape::encode_text(code).to_string())
     }
-}
 
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.options.class_prefix.hash(&mut hasher);
+        let mut aliases = self.options.aliases.iter().collect::<Vec<_>>();
+        aliases.sort();
+        aliases.hash(&mut hasher);
+        hasher.finish()
+    }
+}
@@ -0,0 +1,131 @@
+use std::io::{Error, Result};
+
+use lazy_static::lazy_static;
+use syntect::parsing::{
+    BasicScopeStackOp, ParseState, Scope, ScopeStack, SyntaxSet, SyntaxReference,
+};
+
+use crate::starry::Highlighter;
+
+// TextMate scopes to the classes GitHub and Starry Night use.
+// The most specific (longest) matching scope wins.
+const SCOPE_CLASSES: &[(&str, &str)] = &[
+    ("comment", "pl-c"),
+    ("punctuation.definition.comment", "pl-c"),
+    ("constant", "pl-c1"),
+    ("constant.character.escape", "pl-cce"),
+    ("entity.name.constant", "pl-c1"),
+    ("variable.other.constant", "pl-c1"),
+    ("variable.language", "pl-c1"),
+    ("support", "pl-c1"),
+    ("meta.property-name", "pl-c1"),
+    ("entity", "pl-e"),
+    ("entity.name", "pl-en"),
+    ("entity.name.tag", "pl-ent"),
+    ("entity.other.attribute-name", "pl-e"),
+    ("support.function", "pl-en"),
+    ("keyword", "pl-k"),
+    ("storage", "pl-k"),
+    ("storage.modifier.import", "pl-smi"),
+    ("storage.modifier.package", "pl-smi"),
+    ("variable", "pl-v"),
+    ("variable.parameter.function", "pl-smi"),
+    ("string", "pl-s"),
+    ("string.regexp", "pl-sr"),
+    ("punctuation.definition.string", "pl-pds"),
+    ("punctuation.section.embedded", "pl-pse"),
+    ("markup.heading", "pl-mh"),
+    ("markup.bold", "pl-mb"),
+    ("markup.italic", "pl-mi"),
+    ("markup.inserted", "pl-mi1"),
+    ("markup.deleted", "pl-md"),
+    ("markup.changed", "pl-mc"),
+    ("markup.list", "pl-ml"),
+    ("markup.ignored", "pl-mi2"),
+    ("markup.untracked", "pl-mi2"),
+    ("meta.diff.range", "pl-mdr"),
+    ("meta.separator", "pl-ms"),
+    ("invalid.broken", "pl-bu"),
+    ("invalid.deprecated", "pl-bu"),
+    ("invalid.unimplemented", "pl-bu"),
+    ("invalid.illegal", "pl-ii"),
+    ("carriage-return", "pl-c2"),
+];
+
+lazy_static! {
+    static ref SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref CLASSES: Vec<(Scope, &'static str)> = SCOPE_CLASSES.iter()
+        .map(|(scope, class)| (Scope::new(scope).expect("valid scope"), *class))
+        .collect();
+}
+
+fn class_for(scope: Scope) -> Option<&'static str> {
+    CLASSES.iter()
+        .filter(|(prefix, _)| prefix.is_prefix_of(scope))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, class)| *class)
+}
+
+/// Highlights with syntect's built in syntaxes, without needing nodejs.
+/// Output uses the same `pl-*` classes as Starry Night, so the same stylesheets work.
+#[derive(Default)]
+pub struct SyntectHighlighter;
+
+impl SyntectHighlighter {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn find_syntax(lang: &str) -> Option<&'static SyntaxReference> {
+        SYNTAX_SET.find_syntax_by_token(lang)
+    }
+}
+
+impl Highlighter for SyntectHighlighter {
+    fn highlight(&self, lang: &str, code: &str, _meta: Option<&str>) -> Result<String> {
+        #[cfg(feature = "tree-sitter")]
+        if let Some(highlighted) = crate::grammars::highlight(lang, code) {
+            return highlighted;
+        }
+
+        let Some(syntax) = Self::find_syntax(lang) else {
+            justlogfox::log_debug!("syntect has no syntax for {}", lang);
+            return Ok(html_escape::encode_text(code).to_string());
+        };
+
+        let mut state = ParseState::new(syntax);
+        let mut scopes = ScopeStack::new();
+        // whether each scope on the stack opened a span
+        let mut spans: Vec<bool> = vec![];
+        let mut html = String::new();
+
+        for line in syntect::util::LinesWithEndings::from(code) {
+            let ops = state.parse_line(line, &SYNTAX_SET)
+                .map_err(|e| Error::other(format!("syntect: {e}")))?;
+            let mut pos = 0;
+            for (i, op) in ops {
+                html.push_str(&html_escape::encode_text(&line[pos..i]));
+                pos = i;
+                scopes.apply_with_hook(&op, |op, _| match op {
+                    BasicScopeStackOp::Push(scope) => {
+                        let class = class_for(scope);
+                        if let Some(class) = class {
+                            html.push_str(&format!("<span class=\"{class}\">"));
+                        }
+                        spans.push(class.is_some());
+                    }
+                    BasicScopeStackOp::Pop => {
+                        if spans.pop() == Some(true) {
+                            html.push_str("</span>");
+                        }
+                    }
+                }).map_err(|e| Error::other(format!("syntect: {e}")))?;
+            }
+            html.push_str(&html_escape::encode_text(&line[pos..]));
+        }
+
+        let still_open = spans.iter().filter(|opened| **opened).count();
+        html.push_str(&"</span>".repeat(still_open));
+        Ok(html)
+    }
+}
@@ -28,4 +28,511 @@ fn list() {
     let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
     let expected = "<ul>\n    <li>a\n    <li>b\n    <li>c\n</ul>\n";
     assert_eq!(String::from_utf8_lossy(&output.into_inner().unwrap()).to_string().as_str(), expected);
-}
\ No newline at end of file
+}
+#[test]
+fn footnotes() {
+    let input = "Here[^a] and here[^b], again[^a].\n\n[^a]: First.\n[^b]: Second.\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::new(false, None, None, 1, true);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup>"));
+    assert!(output.contains("<a href=\"#fn-1\" id=\"fnref-1-2\">1</a>"));
+    assert!(output.contains("<li id=\"fn-2\">Second. <a href=\"#fnref-2\" class=\"footnote-backref\">↩</a>"));
+
+    // labels which aren't usable as ids, or would be the same id
+    let input = "A[^注], b[^脚], c[^a.b], d[^ab].\n\n[^注]: One.\n[^脚]: Two.\n[^a.b]: Three.\n[^ab]: Four.\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    for (number, text) in ["One", "Two", "Three", "Four"].iter().enumerate().map(|(i, text)| (i + 1, text)) {
+        assert!(output.contains(&format!("<a href=\"#fn-{number}\" id=\"fnref-{number}\">{number}</a>")), "{output}");
+        assert!(output.contains(&format!("<li id=\"fn-{number}\">{text}. <a href=\"#fnref-{number}\"")), "{output}");
+    }
+}
+
+#[test]
+fn reference_links() {
+    let input = "[text][Ref] and ![alt][LOGO] and [Red Link]\n\n[ref]: https://example.com \"Example\"\n[logo]: /logo.png\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::new(false, None, None, 1, true);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    let expected = "<p><a href=\"https://example.com\" title=\"Example\">text</a> and <img src=\"/logo.png\" alt=\"alt\" /> and [Red Link]\n";
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn toml_frontmatter() {
+    let input = "+++\ntitle = \"Hello\"\ndraft = true\ntags = [\"a\", \"b\"]\n+++\n\ncontent\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::new(false, None, None, 1, true);
+    let meta = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let toml_json = serde_json::to_value(&meta).unwrap();
+
+    let input = "---\ntitle: Hello\ndraft: true\ntags: [a, b]\n---\n\ncontent\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::new(false, None, None, 1, true);
+    let meta = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let yaml_json = serde_json::to_value(&meta).unwrap();
+
+    assert_eq!(toml_json, yaml_json);
+}
+
+#[test]
+fn bad_frontmatter() {
+    let input = "---\ntitle: ok\nbroken: [\n---\n\ncontent\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::new(false, None, None, 1, true);
+    let err = mdnya::render_markdown(input, &mut output, options).err().unwrap();
+    assert!(matches!(err, mdnya::Error::Frontmatter { line: 4, .. }), "{err}");
+}
+
+#[test]
+fn math() {
+    let input = "Squared: $x^2 < y$\n\n$$\n\\sum_{i=0}^n i\n$$\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_math(true);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<p>Squared: <span class=\"math inline\">x^2 &lt; y</span>\n\n<div class=\"math display\">\\sum_{i=0}^n i</div>\n";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+#[test]
+fn options_from_toml() {
+    let options: mdnya::MdnyaOptions = toml::from_str("wrap_sections = \"section\"\nheading_level = 2\ncode_lines = false\n").unwrap();
+    let input = "# Title\n\n```\ncode\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<h2 id=\"title\">Title</h2>\n\n<section>\n    <pre><code>code</code></pre>\n</section>\n";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+    // the same options, from the setters
+    let options = mdnya::MdnyaOptions::default()
+        .with_wrap_sections(Some("section".into()))
+        .with_heading_level(2)
+        .with_code_lines(false);
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+
+    // None clears the section tag
+    let options = mdnya::MdnyaOptions::default()
+        .with_wrap_sections(Some("section".into()))
+        .with_wrap_sections(None);
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown("# Title\n\ntext\n", &mut output, options).unwrap();
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), "<h1 id=\"title\">Title</h1>\n\n<p>text\n");
+}
+
+struct MetaHighlighter;
+
+impl mdnya::Highlighter for MetaHighlighter {
+    fn highlight(&self, lang: &str, code: &str, meta: Option<&str>) -> std::io::Result<String> {
+        Ok(format!("{lang}|{}|{code}", meta.unwrap_or("none")))
+    }
+}
+
+#[test]
+fn custom_highlighter() {
+    let input = "```rust {linenos=true}\nfn\n```\n\n```py\nx\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default()
+        .with_code_lines(false)
+        .with_highlighter(std::sync::Arc::new(MetaHighlighter));
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<pre data-lang=\"rust\"><code>rust|{linenos=true}|fn</code></pre>\n\n<pre data-lang=\"py\"><code>py|none|x</code></pre>\n";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+// counts the code blocks it is asked to highlight
+#[derive(Clone, Default)]
+struct CountingHighlighter {
+    calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    fingerprint: u64,
+    // return the code escaped, like a highlighter which failed
+    fallback: bool,
+}
+
+impl CountingHighlighter {
+    fn calls(&self) -> usize {
+        self.calls.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl mdnya::Highlighter for CountingHighlighter {
+    fn highlight(&self, lang: &str, code: &str, _meta: Option<&str>) -> std::io::Result<String> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if self.fallback {
+            Ok(code.to_string())
+        } else {
+            Ok(format!("<span class=\"{lang}\">{code}</span>"))
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+}
+
+fn temp_cache_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("mdnya-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn cache_memory_hit() {
+    use mdnya::Highlighter;
+    let counting = CountingHighlighter::default();
+    let cached = mdnya::CachedHighlighter::new(counting.clone());
+    let first = cached.highlight("rust", "fn", None).unwrap();
+    assert_eq!(cached.highlight("rust", "fn", None).unwrap(), first);
+    assert_eq!(counting.calls(), 1);
+    cached.highlight("rust", "fn", Some("{1}")).unwrap();
+    assert_eq!(counting.calls(), 2);
+}
+
+#[test]
+fn cache_disk_hit() {
+    use mdnya::Highlighter;
+    let dir = temp_cache_dir("disk");
+    let first = CountingHighlighter::default();
+    let html = mdnya::CachedHighlighter::new(first.clone()).with_disk_cache(dir.clone())
+        .highlight("rust", "fn", None).unwrap();
+    let second = CountingHighlighter::default();
+    let cached = mdnya::CachedHighlighter::new(second.clone()).with_disk_cache(dir.clone());
+    assert_eq!(cached.highlight("rust", "fn", None).unwrap(), html);
+    assert_eq!((first.calls(), second.calls()), (1, 0));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cache_skips_fallback() {
+    use mdnya::Highlighter;
+    let dir = temp_cache_dir("fallback");
+    let counting = CountingHighlighter { fallback: true, .. Default::default() };
+    let cached = mdnya::CachedHighlighter::new(counting.clone()).with_disk_cache(dir.clone());
+    cached.highlight("rust", "fn", None).unwrap();
+    cached.highlight("rust", "fn", None).unwrap();
+    mdnya::CachedHighlighter::new(counting.clone()).with_disk_cache(dir.clone())
+        .highlight("rust", "fn", None).unwrap();
+    assert_eq!(counting.calls(), 3);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cache_fingerprint() {
+    use mdnya::Highlighter;
+    let dir = temp_cache_dir("fingerprint");
+    let highlight = |fingerprint| {
+        let counting = CountingHighlighter { fingerprint, .. Default::default() };
+        mdnya::CachedHighlighter::new(counting.clone()).with_disk_cache(dir.clone())
+            .highlight("rust", "fn", None).unwrap();
+        counting.calls()
+    };
+    assert_eq!(highlight(1), 1);
+    // other settings miss, and don't remove each other's caches
+    assert_eq!(highlight(2), 1);
+    assert_eq!(highlight(1), 0);
+    assert_eq!(highlight(2), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cache_keeps_other_files() {
+    let dir = temp_cache_dir("other-files");
+    let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 60 * 60);
+    let old = |path: std::path::PathBuf| {
+        std::fs::File::options().append(true).open(&path).unwrap().set_modified(two_days_ago).unwrap();
+        path
+    };
+    // as for files, but only where directories can be opened
+    let old_dir = |path: std::path::PathBuf| {
+        let _ = std::fs::File::open(&path).and_then(|dir| dir.set_modified(two_days_ago));
+    };
+    std::fs::create_dir_all(dir.join("docs/old")).unwrap();
+    std::fs::create_dir_all(dir.join("0.0.1/unmarked")).unwrap();
+    std::fs::write(dir.join("docs/old/important.txt"), "keep").unwrap();
+    std::fs::write(dir.join("docs/notes.txt"), "keep").unwrap();
+    std::fs::write(dir.join("0.0.1/unmarked/notes.txt"), "keep").unwrap();
+    std::fs::write(dir.join("0.0.1/notes.txt"), "keep").unwrap();
+    let kept = ["docs/old/important.txt", "docs/notes.txt", "0.0.1/unmarked/notes.txt", "0.0.1/notes.txt"]
+        .map(|file| old(dir.join(file)));
+    for folder in ["docs/old", "docs", "0.0.1/unmarked"] {
+        old_dir(dir.join(folder));
+    }
+
+    let _ = mdnya::CachedHighlighter::new(CountingHighlighter::default()).with_disk_cache(dir.clone());
+    for file in kept {
+        assert!(file.is_file(), "{file:?} was removed");
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// highlights the whole block as one multi-line comment
+struct CommentHighlighter;
+
+impl mdnya::Highlighter for CommentHighlighter {
+    fn highlight(&self, _lang: &str, code: &str, _meta: Option<&str>) -> std::io::Result<String> {
+        Ok(format!("<span class=\"pl-c\">{code}</span>\n"))
+    }
+}
+
+#[test]
+fn line_highlights() {
+    let input = "```c {2} startLine=10 {12}\na\nb\nc\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_highlighter(std::sync::Arc::new(CommentHighlighter));
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<pre data-lang=\"c\"><code>\
+        <span class=\"code-line\" data-line=\"10\"><span class=\"pl-c\">a</span></span>\n\
+        <span class=\"code-line\" data-line=\"11\"><span class=\"pl-c\">b</span></span>\n\
+        <span class=\"code-line highlighted\" data-line=\"12\"><span class=\"pl-c\">c</span></span>\
+        </code></pre>\n";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+
+    // an empty block has no lines
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown("```\n```\n", &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), "<pre><code></code></pre>\n");
+}
+
+#[test]
+fn diff_lines() {
+    let input = "```diff-c\n-a\n+b\n c\n```\n\n```diff\n+x\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_highlighter(std::sync::Arc::new(CommentHighlighter));
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<pre data-lang=\"diff-c\"><code>\
+        <span class=\"code-line deleted\">-<span class=\"pl-c\">a</span></span>\n\
+        <span class=\"code-line inserted\">+<span class=\"pl-c\">b</span></span>\n\
+        <span class=\"code-line\"> <span class=\"pl-c\">c</span></span>\
+        </code></pre>\n\n\
+        <pre data-lang=\"diff\"><code><span class=\"code-line inserted\"><span class=\"pl-c\">+x</span></span></code></pre>\n";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+#[test]
+fn code_groups() {
+    let input = "```cs group=hello\nA\n```\n\n```rust group=hello title=\"Rust\"\nB\n```\n\n```py group=other\nC\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_code_lines(false);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    let expected = "<div class=\"code-group\">
+    <div role=\"tablist\">
+        <button type=\"button\" role=\"tab\" id=\"code-hello-tab-1\" aria-controls=\"code-hello-panel-1\" aria-selected=\"true\">cs</button>
+        <button type=\"button\" role=\"tab\" id=\"code-hello-tab-2\" aria-controls=\"code-hello-panel-2\" aria-selected=\"false\" tabindex=\"-1\">Rust</button>
+    </div>
+    <div role=\"tabpanel\" id=\"code-hello-panel-1\" aria-labelledby=\"code-hello-tab-1\">
+        <pre data-lang=\"cs\"><code>A</code></pre>
+    </div>
+    <div role=\"tabpanel\" id=\"code-hello-panel-2\" aria-labelledby=\"code-hello-tab-2\" hidden>
+        <pre data-lang=\"rust\"><code>B</code></pre>
+    </div>
+</div>
+";
+    assert!(output.starts_with(expected), "{output}");
+    assert!(output.contains("id=\"code-other-panel-1\""), "{output}");
+
+    // admonitions and razor blocks aren't tabs
+    let input = "```{note} group=x\nA\n```\n\n```@ group=x\nB\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.starts_with("<div class=\"admonition note\">\n    <div class=\"admonition-title\">group=x</div>"), "{output}");
+    assert!(output.contains("@{\nB\n}"), "{output}");
+    assert!(!output.contains("tablist"), "{output}");
+}
+
+#[test]
+fn admonition_markdown() {
+    let input = "````{note}\nSee [the docs](/docs) #tagged\n\n- one\n\n```{tip}\n`inner`\n```\n````\n";
+    let mut output = BufWriter::new(vec![]);
+    let meta = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let expected = "<div class=\"admonition note\">
+    <div class=\"admonition-title\">Note</div>
+    <p>See <a href=\"/docs\">the docs</a> <span class=\"tag\">tagged</span>
+    <ul>
+        <li>one
+    </ul>
+    <div class=\"admonition tip\">
+        <div class=\"admonition-title\">Tip</div>
+        <p><code>inner</code>
+    </div>
+</div>
+";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+    assert_eq!(serde_json::to_value(&meta).unwrap()["tags"], serde_json::json!(["tagged"]));
+}
+
+#[test]
+fn admonition_references() {
+    let input = "```{note}\nSee [the docs][docs][^1]\n```\n\n[docs]: /docs\n[^1]: A footnote\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<p>See <a href=\"/docs\">the docs</a><sup class=\"footnote-ref\">"), "{output}");
+    assert!(output.contains("A footnote"), "{output}");
+    assert!(!output.contains("[^1]"), "{output}");
+}
+
+#[test]
+fn admonition_unclosed() {
+    let input = "```{note}\n<!-- unclosed\n```\n\n````{tip}\n    indented\n```js\nlet x\n````\n\n[docs]: /docs\n[^1]: A footnote\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<!-- unclosed"), "{output}");
+    assert!(output.contains("<pre><code><span class=\"code-line\">indented</span></code></pre>"), "{output}");
+    assert!(output.contains("let x</span></code></pre>"), "{output}");
+    assert!(!output.contains("[docs]") && !output.contains("[^1]") && !output.contains("<!---->"), "{output}");
+}
+
+#[test]
+fn admonition_headings() {
+    let input = "# Title\n\n```{note}\n## Inside\n\nbody\n```\n\nafter\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_wrap_sections(Some("section".into()));
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<h1 id=\"title\">Title</h1>
+
+<section>
+    <div class=\"admonition note\">
+        <div class=\"admonition-title\">Note</div>
+        <h2 id=\"inside\">Inside</h2>
+        <p>body
+    </div>
+    <p>after
+</section>
+";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+#[test]
+fn collapsible_admonitions() {
+    let input = "```{note-}\nhidden\n```\n\n```{tip+} Shown\nvisible\n```\n\n```{faq} collapsible Why?\nBecause\n```\n\n> [!warning]-\n> careful\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let expected = "<details class=\"admonition note\">
+    <summary class=\"admonition-title\">Note</summary>
+    <p>hidden
+</details>
+
+<details class=\"admonition tip\" open>
+    <summary class=\"admonition-title\">Shown</summary>
+    <p>visible
+</details>
+
+<details class=\"admonition faq\">
+    <summary class=\"admonition-title\">Why?</summary>
+    <p>Because
+</details>
+
+<details class=\"admonition warning\">
+    <summary class=\"admonition-title\">Warning</summary>
+    <p>careful
+</details>
+";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+#[test]
+fn alerts() {
+    let input = "> [!Warning]\n> The code *below*\n>\n> - will panic\n\n> [!tip] Custom title\n\n> [!nope]\n";
+    let mut output = BufWriter::new(vec![]);
+    let _ = mdnya::render_markdown(input, &mut output, mdnya::MdnyaOptions::default()).unwrap();
+    let expected = "<div class=\"admonition warning\">
+    <div class=\"admonition-title\">Warning</div>
+    <p>The code <em>below</em>
+    <ul>
+        <li>will panic
+    </ul>
+</div>
+
+<div class=\"admonition tip\">
+    <div class=\"admonition-title\">Custom title</div>
+</div>
+
+<blockquote>
+    <p>[!nope]
+</blockquote>
+";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+#[test]
+fn code_titles() {
+    let input = "```rust title=\"src/<main>.rs\" data-copy data-theme='dark mode'\nfn\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_code_lines(false);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let expected = "<figure>\n    <figcaption>src/&lt;main&gt;.rs</figcaption>\n    <pre data-lang=\"rust\" data-copy data-theme=\"dark mode\"><code>fn</code></pre>\n</figure>\n";
+    assert_eq!(String::from_utf8(output.into_inner().unwrap()).unwrap(), expected);
+}
+
+#[cfg(feature = "starry-night")]
+#[test]
+fn starry_night_protocol() {
+    // lines that used to be delimiters between mdnya and node
+    let input = "```rust\nlet a = 1;\n\u{4}\n\n\tlet b = 2;\n```\n\n```nope\n<b>\n\u{4}\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_code_lines(false).with_starry_night();
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert_eq!(output.matches('\u{4}').count(), 2, "{output}");
+    assert!(output.contains("<pre data-lang=\"nope\"><code>&lt;b&gt;\n\u{4}</code></pre>"), "{output}");
+}
+
+#[cfg(feature = "starry-night")]
+#[test]
+fn starry_night_options() {
+    let starry = mdnya::StarryOptions::default()
+        .with_alias("ferris", "rust")
+        .with_class_prefix("hl-");
+    let highlighter = std::sync::Arc::new(mdnya::StarryHighlighter::new(starry));
+    let input = "```ferris\nfn main() {}\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_highlighter(highlighter);
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<span class=\"hl-k\">fn</span>"), "{output}");
+    assert!(!output.contains("pl-"), "{output}");
+}
+
+#[cfg(feature = "syntect")]
+#[test]
+fn syntect_highlighting() {
+    let input = "```go\nfunc main() { s := \"<hi>\" }\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_code_lines(false).with_syntect();
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<span class=\"pl-k\">func</span>"), "{output}");
+    assert!(output.contains("&lt;hi&gt;"), "{output}");
+}
+
+#[cfg(all(feature = "syntect", feature = "grammar-rust"))]
+#[test]
+fn tree_sitter_grammar() {
+    let input = "```rs\nfn main() {}\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_code_lines(false).with_syntect();
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<span class=\"keyword\">fn</span>"), "{output}");
+}
+
+#[cfg(all(feature = "syntect", feature = "grammar-html", feature = "grammar-javascript"))]
+#[test]
+fn tree_sitter_injections() {
+    let input = "```html\n<script>document.x = 1; { let document = 2; document; }</script>\n```\n";
+    let mut output = BufWriter::new(vec![]);
+    let options = mdnya::MdnyaOptions::default().with_code_lines(false).with_syntect();
+    let _ = mdnya::render_markdown(input, &mut output, options).unwrap();
+    let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+    assert!(output.contains("<span class=\"tag\">script</span>"), "{output}");
+    assert!(output.contains("<span class=\"keyword\">let</span>"), "{output}");
+    // only the global is a builtin, the local shadowing it is found with the locals query
+    assert_eq!(output.matches("<span class=\"variable-builtin\">document</span>").count(), 1, "{output}");
+}